@@ -0,0 +1,224 @@
+// Single instruction SM83 assembler, RGBDS syntax
+// Matches operands against the disassembler's templates. Operand values are
+// left to the caller's eval, so numbers and symbols work like in the debugger.
+use crate::disasm;
+
+// Operands that are never an expression
+const FIXED: [&str; 24] = [
+    "A", "B", "C", "D", "E", "H", "L", "AF", "BC", "DE", "HL", "SP", "NZ", "Z", "NC",
+    "[BC]", "[DE]", "[HL]", "[HL+]", "[HL-]", "[HLI]", "[HLD]", "[C]", "[$FF00+C]",
+];
+
+// Mnemonic and operands, with fixed operands uppercased and common aliases folded in
+fn split(line: &str) -> (String, Vec<String>) {
+    let line = line.trim();
+    let (mn, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], &line[i..]),
+        None => (line, "")
+    };
+    let mut mn = mn.to_uppercase();
+    let rest: String = rest.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut ops: Vec<String> = if rest.is_empty() { Vec::new() } else { rest.split(',').map(|s| s.to_string()).collect() };
+
+    for o in ops.iter_mut() {
+        let up = o.to_uppercase();
+        if FIXED.contains(&up.as_str()) {
+            *o = match up.as_str() {
+                "[HLI]" => "[HL+]".to_string(),
+                "[HLD]" => "[HL-]".to_string(),
+                "[$FF00+C]" => "[C]".to_string(),
+                _ => up
+            };
+        } else if up.starts_with("SP+") || up.starts_with("SP-") {
+            *o = format!("SP{}", &o[2..]);
+        }
+    }
+
+    match mn.as_str() {
+        "LDI" | "LDD" => {
+            let hl = if mn == "LDI" { "[HL+]" } else { "[HL-]" };
+            for o in ops.iter_mut().filter(|o| *o == "[HL]") {
+                *o = hl.to_string();
+            }
+            mn = "LD".to_string();
+        }
+        "LD" if ops.contains(&"[C]".to_string()) => mn = "LDH".to_string(),
+        "JP" if ops == ["[HL]"] => ops[0] = "HL".to_string(),
+        "ADD" | "ADC" | "SBC" | "SUB" | "AND" | "XOR" | "OR" | "CP" if ops.len() == 1 => ops.insert(0, "A".to_string()),
+        _ => {}
+    }
+    return (mn, ops);
+}
+
+// Assemble line for address addr
+pub fn assemble(line: &str, addr: u16, eval: &dyn Fn(&str) -> Result<u32, String>) -> Result<Vec<u8>, String> {
+    let (mn, ops) = split(line);
+    if mn == "RST" && ops.len() == 1 {
+        let v = eval(&ops[0])?;
+        if v & !0x38 != 0 {
+            return Err(format!("bad RST vector {}", ops[0]));
+        }
+        return Ok(vec![0xC7 | v as u8]);
+    }
+
+    let mut err = format!("can't assemble {}", line.trim());
+    for cb in [false, true].iter() {
+        for op in 0..=255u8 {
+            let tmpl = disasm::template(op, *cb);
+            let (tmn, tops) = split(&tmpl);
+            if tmpl.is_empty() || tmn != mn || tops.len() != ops.len() {
+                continue;
+            }
+            let mut arg = None;
+            let mut ok = true;
+            for (t, o) in tops.iter().zip(ops.iter()) {
+                if t == o {
+                    continue;
+                }
+                match operand(t, o) {
+                    Some(v) => arg = Some((t.clone(), v)),
+                    None => ok = false,
+                }
+            }
+            if !ok {
+                continue;
+            }
+
+            let mut bytes = if *cb { vec![0xCB, op] } else { vec![op] };
+            if op == 0x10 && !cb {
+                bytes.push(0); // STOP
+            }
+            if let Some((t, v)) = arg {
+                match encode(&t, &v, addr, eval) {
+                    Ok(mut b) => bytes.append(&mut b),
+                    Err(e) => {
+                        err = e;
+                        continue;
+                    }
+                }
+            }
+            return Ok(bytes);
+        }
+    }
+    return Err(err);
+}
+
+// The expression in operand o if it fits template operand t
+fn operand(t: &str, o: &str) -> Option<String> {
+    let ph = ["n16", "a16", "n8", "a8", "e8", "s8"].iter().find(|p| t.contains(*p))?;
+    let i = t.find(ph).unwrap();
+    let (pre, suf) = (&t[..i], &t[i + ph.len()..]);
+    if !o.starts_with(pre) || !o.ends_with(suf) || o.len() <= pre.len() + suf.len() {
+        return None;
+    }
+    let v = &o[pre.len()..o.len() - suf.len()];
+    if FIXED.contains(&v.to_uppercase().as_str()) || (pre.is_empty() && (v.starts_with('[') || v.starts_with("SP"))) {
+        return None;
+    }
+    return Some(v.to_string());
+}
+
+fn encode(t: &str, v: &str, addr: u16, eval: &dyn Fn(&str) -> Result<u32, String>) -> Result<Vec<u8>, String> {
+    if t.contains("s8") {
+        let n = match v.strip_prefix('-') {
+            Some(m) => -(eval(m)? as i32),
+            None => eval(v.strip_prefix('+').unwrap_or(v))? as i32
+        };
+        if !(-128..=127).contains(&n) {
+            return Err(format!("offset {} out of range", v));
+        }
+        return Ok(vec![n as u8]);
+    }
+
+    let n = eval(v)?;
+    return if t.contains("n16") || t.contains("a16") {
+        Ok(vec![n as u8, (n >> 8) as u8])
+    } else if t.contains("a8") {
+        if n > 0xFF && !(0xFF00..=0xFFFF).contains(&n) {
+            return Err(format!("{} is not in $FF00-$FFFF", v));
+        }
+        Ok(vec![n as u8])
+    } else if t.contains("e8") {
+        let d = n as i32 - (addr as i32 + 2);
+        if !(-128..=127).contains(&d) {
+            return Err(format!("{} is out of JR range", v));
+        }
+        Ok(vec![d as u8])
+    } else {
+        if n > 0xFF {
+            return Err(format!("{} doesn't fit in a byte", v));
+        }
+        Ok(vec![n as u8])
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(e: &str) -> Result<u32, String> {
+        return match e.strip_prefix('$') {
+            Some(h) => u32::from_str_radix(h, 16).map_err(|_| format!("bad number {}", e)),
+            None => e.parse().map_err(|_| format!("unknown symbol {}", e))
+        };
+    }
+
+    fn asm(line: &str) -> Result<Vec<u8>, String> {
+        return assemble(line, 0xC000, &eval);
+    }
+
+    // Every defined opcode disassembles to text that assembles back to it
+    #[test]
+    fn round_trip() {
+        for cb in [false, true].iter() {
+            for op in 0..=255u8 {
+                if disasm::template(op, *cb).is_empty() || (op == 0xCB && !cb) {
+                    continue;
+                }
+                let bytes = match (*cb, op) {
+                    (true, _) => vec![0xCB, op, 0, 0],
+                    (false, 0x10) => vec![0x10, 0x00, 0, 0],
+                    _ => vec![op, 0xF4, 0x12, 0]
+                };
+                let ins = disasm::decode(|a| bytes[(a - 0xC000) as usize], 0xC000);
+                let text = ins.text_with(&|_| None);
+                assert_eq!(asm(&text), Ok(bytes[..ins.len as usize].to_vec()), "{}", text);
+                assert_eq!(asm(&text.to_lowercase()), Ok(bytes[..ins.len as usize].to_vec()), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(asm("ldi a,[hl]"), Ok(vec![0x2A]));
+        assert_eq!(asm("ldd [hl],a"), Ok(vec![0x32]));
+        assert_eq!(asm("ld a,[hli]"), Ok(vec![0x2A]));
+        assert_eq!(asm("ld [$ff00+c],a"), Ok(vec![0xE2]));
+        assert_eq!(asm("ld a,[c]"), Ok(vec![0xF2]));
+        assert_eq!(asm("jp [hl]"), Ok(vec![0xE9]));
+        assert_eq!(asm("add b"), Ok(vec![0x80]));
+        assert_eq!(asm("cp $90"), Ok(vec![0xFE, 0x90]));
+        assert_eq!(asm("  ld   hl , sp - $10 "), Ok(vec![0xF8, 0xF0]));
+        assert_eq!(asm("ldh [$44],a"), Ok(vec![0xE0, 0x44]));
+        assert_eq!(asm("jr $C000"), Ok(vec![0x18, 0xFE]));
+        assert_eq!(asm("rst $38"), Ok(vec![0xFF]));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(asm("frob a"), Err("can't assemble frob a".to_string()));
+        assert_eq!(asm("ld a,"), Err("can't assemble ld a,".to_string()));
+        assert_eq!(asm("ld a,b,c"), Err("can't assemble ld a,b,c".to_string()));
+        assert_eq!(asm("ld [hl],[hl]"), Err("can't assemble ld [hl],[hl]".to_string()));
+        assert_eq!(asm("ld [bc],b"), Err("can't assemble ld [bc],b".to_string()));
+        assert_eq!(asm("bit 8,a"), Err("can't assemble bit 8,a".to_string()));
+        assert_eq!(asm("push sp"), Err("can't assemble push sp".to_string()));
+        assert_eq!(asm("ld a,$100"), Err("$100 doesn't fit in a byte".to_string()));
+        assert_eq!(asm("ldh [$1234],a"), Err("$1234 is not in $FF00-$FFFF".to_string()));
+        assert_eq!(asm("jr $D000"), Err("$D000 is out of JR range".to_string()));
+        assert_eq!(asm("add sp,$80"), Err("offset $80 out of range".to_string()));
+        assert_eq!(asm("rst $09"), Err("bad RST vector $09".to_string()));
+        assert_eq!(asm("ld a,nowhere"), Err("unknown symbol nowhere".to_string()));
+        assert_eq!(asm("ld hl,$1G"), Err("bad number $1G".to_string()));
+    }
+}
@@ -0,0 +1,239 @@
+// Code/data logger
+// The .cdl file is Mesen's: "CDLv2", the rom's CRC32 little endian, then a flag
+// byte per rom byte. The low four bits are Mesen's code/data/jump target/sub entry
+// flags, bits 4-6 are ours. Files without the header, as older Mesen versions
+// wrote them, load too. Loading an existing file merges into it.
+use crate::consts::*;
+use crate::cpu::Cpu;
+use crate::disasm;
+use crate::mem::Mem;
+use std::fs;
+use std::path::Path;
+
+pub const CODE: u8 = 0x01; // part of an executed instruction
+pub const DATA: u8 = 0x02; // read by the cpu as data
+pub const JUMP: u8 = 0x04; // branched to
+pub const SUB: u8 = 0x08; // called or RST to
+pub const OPERAND: u8 = 0x10; // instruction byte after the opcode
+pub const TILE: u8 = 0x20; // copied into tile data
+pub const DMA: u8 = 0x40; // OAM DMA or HDMA source
+
+const MAGIC: &[u8] = b"CDLv2";
+
+pub struct Cdl {
+    pub flags: Vec<u8>,
+    crc: u32, // of the rom, for the header
+    fetch: (u16, u16), // pc and length of the instruction being run
+    branch: Option<(u16, u8)>, // where it goes if it branches, and the flag for that
+    last: Option<(usize, u8)>, // last rom data read, offset and value
+}
+
+impl Cdl {
+    pub fn new(size: usize, crc: u32) -> Cdl {
+        return Cdl {
+            flags: vec![0; size],
+            crc,
+            fetch: (0, 0),
+            branch: None,
+            last: None,
+        };
+    }
+
+    // Flags of a .cdl file for the rom of size and crc, with or without the header
+    pub fn parse(dat: &[u8], size: usize, crc: u32) -> Result<Vec<u8>, String> {
+        let flags = match dat.strip_prefix(MAGIC) {
+            Some(d) if d.len() >= 4 => {
+                let got = u32::from_le_bytes([d[0], d[1], d[2], d[3]]);
+                if got != crc {
+                    return Err(format!("is for another rom, CRC32 {:08X} not {:08X}", got, crc));
+                }
+                &d[4..]
+            }
+            _ => dat
+        };
+        if flags.len() != size {
+            return Err(format!("is {} bytes, the rom is {}", flags.len(), size));
+        }
+        return Ok(flags.to_vec());
+    }
+
+    // New log for a rom of size and crc, merged with path if it exists
+    pub fn load(path: &Path, size: usize, crc: u32) -> Result<Cdl, String> {
+        let mut cdl = Cdl::new(size, crc);
+        if !path.exists() {
+            return Ok(cdl);
+        }
+        let err = |e: String| format!("{}: {}", path.display(), e);
+        let old = fs::read(path).map_err(|e| err(e.to_string()))?;
+        for (f, o) in cdl.flags.iter_mut().zip(Cdl::parse(&old, size, crc).map_err(err)?) {
+            *f |= o;
+        }
+        return Ok(cdl);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut dat = MAGIC.to_vec();
+        dat.extend_from_slice(&self.crc.to_le_bytes());
+        dat.extend_from_slice(&self.flags);
+        return fs::write(path, dat).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    fn mark(&mut self, mem: &Mem, addr: u16, f: u8) {
+        if let Some(off) = mem.rom_offset(addr) {
+            self.flags[off] |= f;
+        }
+    }
+
+    // Before the cpu runs an instruction at pc
+    pub fn begin(&mut self, cpu: &Cpu, mem: &Mem) {
+        let ins = disasm::decode(|a| mem.read(a), cpu.pc);
+        self.fetch = (cpu.pc, ins.len);
+        self.branch = match ins.target() {
+            Some(t) => Some((t, if ins.is_call() { SUB } else { JUMP })),
+            None if !ins.cb && ins.op == 0xE9 => Some((cpu.get_hilo(H, L), JUMP)),
+            None => None
+        };
+    }
+
+    pub fn end(&mut self, cpu: &Cpu, mem: &Mem) {
+        if let Some((t, f)) = self.branch.take() {
+            if cpu.pc == t {
+                self.mark(mem, t, f);
+            }
+        }
+    }
+
+    // Every cpu read
+    pub fn read(&mut self, mem: &Mem, addr: u16, val: u8) {
+        let off = match mem.rom_offset(addr) {
+            Some(o) => o,
+            None => return
+        };
+        let (pc, len) = self.fetch;
+        let i = addr.wrapping_sub(pc);
+        self.flags[off] |= if i == 0 {
+            CODE
+        } else if i < len {
+            CODE | OPERAND
+        } else {
+            self.last = Some((off, val));
+            DATA
+        };
+    }
+
+    // Every cpu write, a tile data write of the byte just read from rom
+    // counts as a copy of it
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if let (0x8000..=0x97FF, Some((off, v))) = (addr, self.last) {
+            if v == val {
+                self.flags[off] |= TILE;
+                self.last = None;
+            }
+        }
+    }
+
+    pub fn dma(&mut self, mem: &Mem, src: u16, len: u16) {
+        for i in 0..len {
+            self.mark(mem, src.wrapping_add(i), DMA);
+        }
+    }
+
+    // Coverage per bank
+    pub fn report(&self) {
+        for (bank, b) in self.flags.chunks(0x4000).enumerate() {
+            let code = b.iter().filter(|&&f| f & CODE != 0).count();
+            let data = b.iter().filter(|&&f| f & CODE == 0 && f != 0).count();
+            let pct = |n: usize| n as f64 * 100. / b.len() as f64;
+            println!("bank {:02X}: {:5.1}% code {:5.1}% data {:5.1}% unknown",
+                bank, pct(code), pct(data), pct(b.len() - code - data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const CRC: u32 = 0x1234ABCD;
+
+    fn tmp(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("gb-cdl-{}-{}.cdl", std::process::id(), name));
+    }
+
+    // A file the way Mesen writes it
+    fn mesen(crc: u32, flags: &[u8]) -> Vec<u8> {
+        let mut dat = b"CDLv2".to_vec();
+        dat.extend_from_slice(&crc.to_le_bytes());
+        dat.extend_from_slice(flags);
+        return dat;
+    }
+
+    fn load(name: &str, dat: &[u8]) -> Result<Cdl, String> {
+        let path = tmp(name);
+        fs::write(&path, dat).unwrap();
+        let cdl = Cdl::load(&path, 0x8000, CRC);
+        fs::remove_file(&path).unwrap();
+        return cdl;
+    }
+
+    #[test]
+    fn mesen_file() {
+        let mut flags = vec![0; 0x8000];
+        flags[0x0100] = CODE | JUMP;
+        flags[0x0101] = CODE;
+        flags[0x0150] = CODE | SUB;
+        flags[0x4000] = DATA;
+        assert_eq!(load("mesen", &mesen(CRC, &flags)).unwrap().flags, flags);
+        // Older files have no header
+        assert_eq!(load("raw", &flags).unwrap().flags, flags);
+    }
+
+    #[test]
+    fn merge() {
+        let path = tmp("merge");
+        let mut old = vec![0; 0x8000];
+        old[0x0000] = CODE;
+        old[0x0010] = DATA;
+        old[0x4000] = TILE | DATA;
+        fs::write(&path, mesen(CRC, &old)).unwrap();
+
+        let mem = Mem::default();
+        let mut cdl = Cdl::load(&path, 0x8000, CRC).unwrap();
+        assert_eq!(cdl.flags, old);
+        cdl.read(&mem, 0x0000, 0); // fetch is (0, 0), so this is the opcode
+        cdl.read(&mem, 0x0010, 0);
+        cdl.read(&mem, 0x0020, 0x12);
+        cdl.write(0x8000, 0x12);
+        cdl.dma(&mem, 0x4000, 2);
+        cdl.save(&path).unwrap();
+
+        let new = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(new[..9], mesen(CRC, &[])[..]);
+        let new = &new[9..];
+        assert_eq!(new.len(), 0x8000);
+        assert_eq!(new[0x0000], CODE);
+        assert_eq!(new[0x0010], DATA);
+        assert_eq!(new[0x0020], DATA | TILE);
+        assert_eq!(new[0x4000], TILE | DATA | DMA);
+        assert_eq!(new[0x4001], DMA);
+        assert_eq!(new.iter().filter(|&&f| f != 0).count(), 5);
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let cdl = Cdl::load(&tmp("missing"), 0x8000, CRC).unwrap();
+        assert_eq!(cdl.flags, vec![0; 0x8000]);
+    }
+
+    #[test]
+    fn wrong_rom() {
+        let err = load("crc", &mesen(0x1234ABCE, &[0; 0x8000])).err().unwrap();
+        assert!(err.ends_with(": is for another rom, CRC32 1234ABCE not 1234ABCD"), "{}", err);
+        let err = load("size", &mesen(CRC, &[CODE; 0x4000])).err().unwrap();
+        assert!(err.ends_with(": is 16384 bytes, the rom is 32768"), "{}", err);
+        let err = load("rawsize", &[CODE; 0x4000]).err().unwrap();
+        assert!(err.ends_with(": is 16384 bytes, the rom is 32768"), "{}", err);
+    }
+}
@@ -17,6 +17,7 @@ pub const FL_Z: u8 = 1 << 7; // Zero
 // W, H
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
+#[cfg(any(feature = "sdl", feature = "minifb"))]
 pub const SCALE: usize = 4;
 
 // VRAM addrs
@@ -37,6 +38,7 @@ pub const PAL_0: (u8, u8, u8) = (224, 248, 208);
 pub const PAL_1: (u8, u8, u8) = (136, 192, 112);
 pub const PAL_2: (u8, u8, u8) = (52, 104, 86);
 pub const PAL_3: (u8, u8, u8) = (8, 24, 32);
+pub const SHADES: [(u8, u8, u8); 4] = [PAL_0, PAL_1, PAL_2, PAL_3];
 
 // Input addr(s)
 pub const JOYP: u16 = 0xFF00;
@@ -2,6 +2,26 @@ use crate::consts::*;
 use crate::mem;
 
 
+// Why a call frame was pushed
+#[derive(Clone, Copy, PartialEq)]
+pub enum Cause {
+    Call,
+    Rst,
+    Int(u8),
+}
+
+// Shadow call stack entry
+#[derive(Clone)]
+pub struct Frame {
+    pub site: u16, // the call, or the instruction an interrupt came in before
+    pub target: u16,
+    pub bank: u16, // bank of target
+    pub cause: Cause,
+    pub ret: u16, // return address pushed
+    pub sp: u16, // where it was pushed
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     pub regs: [u8; 8], // Regs A-F, H,L
     pub sp: u16, // Stack pointer
@@ -13,6 +33,8 @@ pub struct Cpu {
     pub halt: u8, // HALT mode
     pub stop: u8, // STOP mode
     pub clk: u64, // clock counter
+    pub calls: Vec<Frame>, // shadow call stack
+    pub bad_ret: Option<(u16, u16, Frame)>, // RET that didn't match its call: RET addr, where it went, the frame
 }
 
 impl Cpu {
@@ -37,32 +59,55 @@ impl Cpu {
         };
     }
 
-    pub fn ld_16(&mut self, gb_mem: &mem::Mem, rh: usize, rl: usize) {
+    // Record a frame once pc and sp point into the callee
+    fn enter(&mut self, gb_mem: &impl mem::Bus, site: u16, ret: u16, cause: Cause) {
+        // Frames at or below sp were overwritten by this push
+        while self.calls.last().is_some_and(|f| f.sp <= self.sp) {
+            self.calls.pop();
+        }
+        self.calls.push(Frame { site, target: self.pc, bank: gb_mem.bank_of(self.pc), cause, ret, sp: self.sp });
+    }
+
+    // Pop frames after a RET from sp, flagging it if the return doesn't match the top frame
+    fn leave(&mut self, site: u16, sp: u16) {
+        let top = match self.calls.last() {
+            Some(f) => f.clone(),
+            None => return
+        };
+        while self.calls.last().is_some_and(|f| f.sp <= sp) {
+            self.calls.pop();
+        }
+        if top.sp != sp || top.ret != self.pc {
+            self.bad_ret = Some((site, self.pc, top));
+        }
+    }
+
+    pub fn ld_16(&mut self, gb_mem: &impl mem::Bus, rh: usize, rl: usize) {
         self.clk += 4;
-        self.regs[rl] = gb_mem.read(self.pc);
+        self.regs[rl] = gb_mem.read_at(self.clk, self.pc);
         self.clk += 4;
         self.pc = self.pc.wrapping_add(1);
-        self.regs[rh] = gb_mem.read(self.pc);
+        self.regs[rh] = gb_mem.read_at(self.clk, self.pc);
         self.pc = self.pc.wrapping_add(1);
         self.clk += 4
     }
 
-    pub fn ld_8(&mut self, gb_mem: &mem::Mem, r: usize) {
+    pub fn ld_8(&mut self, gb_mem: &impl mem::Bus, r: usize) {
         self.clk += 4;
-        self.regs[r] = gb_mem.read(self.pc);
+        self.regs[r] = gb_mem.read_at(self.clk, self.pc);
         self.pc = self.pc.wrapping_add(1);
         self.clk += 4;
     }
 
-    pub fn ld_p16_8(&mut self, gb_mem: &mut mem::Mem, rh: usize, rl: usize, r8: usize) {
+    pub fn ld_p16_8(&mut self, gb_mem: &mut impl mem::Bus, rh: usize, rl: usize, r8: usize) {
         self.clk += 4;
-        gb_mem.write(self.get_hilo(rh, rl), self.regs[r8]);
+        gb_mem.write_at(self.clk, self.get_hilo(rh, rl), self.regs[r8]);
         self.clk += 4;
     }
 
-    pub fn ld_8_p16(&mut self, gb_mem: &mem::Mem, r8: usize, rh: usize, rl: usize) {
+    pub fn ld_8_p16(&mut self, gb_mem: &impl mem::Bus, r8: usize, rh: usize, rl: usize) {
         self.clk += 4;
-        self.regs[r8] = gb_mem.read(self.get_hilo(rh, rl));
+        self.regs[r8] = gb_mem.read_at(self.clk, self.get_hilo(rh, rl));
     }
 
     pub fn inc_16(&mut self, rh: usize, rl: usize) {
@@ -188,82 +233,88 @@ impl Cpu {
         self.clk += 4;
     }
 
-    pub fn rst_addr16(&mut self, gb_mem: &mut mem::Mem, addr: u16) {
+    pub fn rst_addr16(&mut self, gb_mem: &mut impl mem::Bus, addr: u16) {
+        let ret = self.pc;
         self.clk += 8;
         self.sp = (self.sp as i32 - 1) as u16;
-        gb_mem.write(self.sp, (self.pc >> 8) as u8);
+        gb_mem.write_at(self.clk, self.sp, (self.pc >> 8) as u8);
         self.clk += 4;
         self.sp = (self.sp as i32 - 1) as u16;
-        gb_mem.write(self.sp, self.pc as u8);
+        gb_mem.write_at(self.clk, self.sp, self.pc as u8);
         self.pc = addr;
         self.clk += 4;
+        self.enter(gb_mem, ret.wrapping_sub(1), ret, Cause::Rst);
     }
 
-    pub fn push_16(&mut self, gb_mem: &mut mem::Mem, rh: usize, rl: usize) {
+    pub fn push_16(&mut self, gb_mem: &mut impl mem::Bus, rh: usize, rl: usize) {
         self.clk += 8;
         self.sp = (self.sp as i32 - 1) as u16;
-        gb_mem.write(self.sp, self.regs[rh]);
+        gb_mem.write_at(self.clk, self.sp, self.regs[rh]);
         self.clk += 4;
         self.sp = (self.sp as i32 - 1) as u16;
-        gb_mem.write(self.sp, self.regs[rl]);
+        gb_mem.write_at(self.clk, self.sp, self.regs[rl]);
         self.clk += 4;
     }
 
-    pub fn pop_16(&mut self, gb_mem: &mem::Mem, rh: usize, rl: usize) {
+    pub fn pop_16(&mut self, gb_mem: &impl mem::Bus, rh: usize, rl: usize) {
         self.clk += 4;
-        self.regs[rl] = gb_mem.read(self.sp);
+        self.regs[rl] = gb_mem.read_at(self.clk, self.sp);
         self.clk += 4;
         self.sp = (self.sp as u32 + 1) as u16;
-        self.regs[rh] = gb_mem.read(self.sp);
+        self.regs[rh] = gb_mem.read_at(self.clk, self.sp);
         self.sp = (self.sp as u32 + 1) as u16;
         self.clk += 4;
     }
 
-    pub fn call_addr16(&mut self, gb_mem: &mut mem::Mem, cond: bool) {
+    pub fn call_addr16(&mut self, gb_mem: &mut impl mem::Bus, cond: bool) {
         if cond {
             self.clk += 4;
-            let mut tmp: u32 = gb_mem.read(self.pc) as u32;
+            let mut tmp: u32 = gb_mem.read_at(self.clk, self.pc) as u32;
             self.clk += 4;
             self.pc = self.pc.wrapping_add(1);
-            tmp |= (gb_mem.read(self.pc) as u32) << 8;
+            tmp |= (gb_mem.read_at(self.clk, self.pc) as u32) << 8;
             self.pc = self.pc.wrapping_add(1);
             self.clk += 8;
             self.sp = (self.sp as i32 - 1) as u16;
-            gb_mem.write(self.sp, (self.pc >> 8) as u8);
+            gb_mem.write_at(self.clk, self.sp, (self.pc >> 8) as u8);
             self.clk += 4;
             self.sp = ((self.sp as i32 - 1)) as u16;
-            gb_mem.write(self.sp, (self.pc & 0x00FF) as u8);
+            gb_mem.write_at(self.clk, self.sp, (self.pc & 0x00FF) as u8);
+            let ret = self.pc;
             self.pc = tmp as u16;
             self.clk += 4;
+            self.enter(gb_mem, ret.wrapping_sub(3), ret, Cause::Call);
         } else {
             self.pc = (self.pc as u32 + 2) as u16;
             self.clk += 12;
         }
     }
 
-    pub fn ret(&mut self, gb_mem: &mem::Mem, cond: bool) {
+    pub fn ret(&mut self, gb_mem: &impl mem::Bus, cond: bool) {
         if cond {
+            let (site, sp) = (self.pc.wrapping_sub(1), self.sp);
             self.clk += 4;
-            let mut tmp: u32 = gb_mem.read(self.sp) as u32;
+            let mut tmp: u32 = gb_mem.read_at(self.clk, self.sp) as u32;
             self.clk += 4;
             self.sp = (self.sp as u32 + 1) as u16;
-            tmp |= (gb_mem.read(self.sp) as u32) << 8;
+            tmp |= (gb_mem.read_at(self.clk, self.sp) as u32) << 8;
             self.sp = (self.sp as u32 + 1) as u16;
             self.clk += 4;
             self.pc = tmp as u16;
-            self.clk += 8
+            self.clk += 8;
+            self.leave(site, sp);
         } else {
             self.clk += 8;
         }
     }
 
-    pub fn jp_addr16(&mut self, gb_mem: &mem::Mem, cond: bool) {
+    pub fn jp_addr16(&mut self, gb_mem: &impl mem::Bus, cond: bool) {
         if cond {
             self.clk += 4;
-            let mut tmp: u32 = gb_mem.read(self.pc) as u32;
+            let mut tmp: u32 = gb_mem.read_at(self.clk, self.pc) as u32;
             self.clk += 4;
             self.pc = self.pc.wrapping_add(1);
-            tmp |= (gb_mem.read(self.pc) as u32) << 8;
+            tmp |= (gb_mem.read_at(self.clk, self.pc) as u32) << 8;
             self.pc = self.pc.wrapping_add(1);
             self.clk += 4;
             self.pc = tmp as u16;
@@ -274,10 +325,10 @@ impl Cpu {
         }
     }
 
-    pub fn jr_addr8(&mut self, gb_mem: &mem::Mem, cond: bool) {
+    pub fn jr_addr8(&mut self, gb_mem: &impl mem::Bus, cond: bool) {
         if cond {
             self.clk += 4;
-            let tmp = gb_mem.read(self.pc) as u32;
+            let tmp = gb_mem.read_at(self.clk, self.pc) as u32;
             self.pc = self.pc.wrapping_add(1);
             self.pc = ((tmp as i8) as i32 + self.pc as i32) as u16;
             self.clk += 8;
@@ -359,11 +410,11 @@ impl Cpu {
         self.clk += 4;
     }
 
-    pub fn bitnum_phl(&mut self, gb_mem: &mem::Mem, bit: u8) {
+    pub fn bitnum_phl(&mut self, gb_mem: &impl mem::Bus, bit: u8) {
         self.clk += 4;
         self.set_flag(false, FL_N);
         self.set_flag(true, FL_H);
-        self.set_flag((gb_mem.read(self.get_hilo(H, L)) & (1 << bit)) == 0, FL_Z);
+        self.set_flag((gb_mem.read_at(self.clk, self.get_hilo(H, L)) & (1 << bit)) == 0, FL_Z);
         self.clk += 4;
     }
 
@@ -372,11 +423,11 @@ impl Cpu {
         self.clk += 4;
     }
 
-    pub fn resnum_phl(&mut self, gb_mem: &mut mem::Mem, bit: u8) {
+    pub fn resnum_phl(&mut self, gb_mem: &mut impl mem::Bus, bit: u8) {
         self.clk += 4;
-        let tmp = gb_mem.read(self.get_hilo(H, L));
+        let tmp = gb_mem.read_at(self.clk, self.get_hilo(H, L));
         self.clk += 4;
-        gb_mem.write(self.get_hilo(H, L), tmp & (!(1 << bit)));
+        gb_mem.write_at(self.clk, self.get_hilo(H, L), tmp & (!(1 << bit)));
         self.clk += 4;
     }
 
@@ -385,11 +436,11 @@ impl Cpu {
         self.clk += 4;
     }
 
-    pub fn setnum_phl(&mut self, gb_mem: &mut mem::Mem, bit: u8) {
+    pub fn setnum_phl(&mut self, gb_mem: &mut impl mem::Bus, bit: u8) {
         self.clk += 4;
-        let tmp = gb_mem.read(self.get_hilo(H, L));
+        let tmp = gb_mem.read_at(self.clk, self.get_hilo(H, L));
         self.clk += 4;
-        gb_mem.write(self.get_hilo(H, L), tmp | (1 << bit));
+        gb_mem.write_at(self.clk, self.get_hilo(H, L), tmp | (1 << bit));
         self.clk += 4;
     }
 
@@ -413,6 +464,8 @@ impl Default for Cpu {
             halt: 0,
             stop: 0,
             clk: 0,
+            calls: Vec::new(),
+            bad_ret: None,
         };
         gb_cpu.set_hilo(A, F, 0x01B0);
         gb_cpu.set_hilo(B, C, 0x0013);
@@ -423,8 +476,8 @@ impl Default for Cpu {
     }
 }
 
-pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
-    let (int_e, int_f) = (gb_mem.read(PINT_E), gb_mem.read(PINT_F));
+pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut impl mem::Bus) {
+    let (int_e, int_f) = (gb_mem.peek(PINT_E), gb_mem.peek(PINT_F));
 
 
     if (int_e & int_f) != 0 {
@@ -436,14 +489,19 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.ime = false;
             let n = (int_e & int_f).trailing_zeros();
             if n < 5 {
-                gb_mem.write(PINT_F, int_f & !(1 << n));
-    
+                gb_mem.put(PINT_F, int_f & !(1 << n));
+
+                // 2 idle M-cycles, push PC, then the jump
+                gb_cpu.clk += 8;
                 gb_cpu.sp = (gb_cpu.sp as i32 - 1) as u16;
-                gb_mem.write(gb_cpu.sp, (gb_cpu.pc >> 8) as u8);
+                gb_mem.write_at(gb_cpu.clk, gb_cpu.sp, (gb_cpu.pc >> 8) as u8);
+                gb_cpu.clk += 4;
                 gb_cpu.sp = (gb_cpu.sp as i32 - 1) as u16;
-                gb_mem.write(gb_cpu.sp, (gb_cpu.pc & 0xFF) as u8);
+                gb_mem.write_at(gb_cpu.clk, gb_cpu.sp, (gb_cpu.pc & 0xFF) as u8);
+                let ret = gb_cpu.pc;
                 gb_cpu.pc = 0x40 | ((n as u16) << 3);
-                gb_cpu.clk += 20;
+                gb_cpu.clk += 8;
+                gb_cpu.enter(gb_mem, ret, ret, Cause::Int(n as u8));
                 return;
             }
         }
@@ -465,7 +523,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         return;
     }
 
-    let mut opcode: u8 = gb_mem.read(gb_cpu.pc);
+    let mut opcode: u8 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
     /*println!("OP: {:#X} PC: {:#X}, AF: {:#X}, BC: {:#X}, DE: {:#X}, HL: {:#X}, SP: {:#X}", 
         opcode, gb_cpu.pc, gb_cpu.get_hilo(A, F), gb_cpu.get_hilo(B, C), gb_cpu.get_hilo(D, E), gb_cpu.get_hilo(H, L),
         gb_cpu.sp
@@ -496,16 +554,16 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x08 => // LD [nnnn],SP - 5
         {
             gb_cpu.clk += 4;
-            let mut tmp: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.clk += 4;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
-            tmp |= (gb_mem.read(gb_cpu.pc) as u32) << 8;
+            tmp |= (gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32) << 8;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
-            gb_mem.write(tmp as u16, gb_cpu.sp as u8);
+            gb_mem.write_at(gb_cpu.clk, tmp as u16, gb_cpu.sp as u8);
             tmp += 1;
             gb_cpu.clk += 4;
-            gb_mem.write(tmp as u16, (gb_cpu.sp >> 8) as u8);
+            gb_mem.write_at(gb_cpu.clk, tmp as u16, (gb_cpu.sp >> 8) as u8);
             gb_cpu.clk += 4;
         },
         0x09 => // ADD HL,BC - 2
@@ -530,11 +588,15 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x10 => // STOP - 1*
         {
             gb_cpu.clk += 4;
-            if gb_mem.read(gb_cpu.pc) != 0 {
+            if gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) != 0 {
                 println!("BAD STOP!");
             }
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
+            // The switch stops the cpu too, Gb::exec adds that
+            if gb_mem.speed_switch() {
+                return;
+            }
             gb_cpu.stop = 1;
             return;
         },
@@ -589,7 +651,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x22 => // LD [HL+],A - 2
         {
             gb_cpu.clk += 4;
-            gb_mem.write(gb_cpu.get_hilo(H, L), gb_cpu.regs[A]);
+            gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), gb_cpu.regs[A]);
             gb_cpu.set_hilo(H, L, (gb_cpu.get_hilo(H, L) as u32 + 1) as u16);
             gb_cpu.clk += 4;
         },
@@ -621,7 +683,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x2A => // LD A,[HL+] - 2
         {
             gb_cpu.clk += 4;
-            gb_cpu.regs[A] = gb_mem.read(gb_cpu.get_hilo(H, L));
+            gb_cpu.regs[A] = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             gb_cpu.set_hilo(H, L, (gb_cpu.get_hilo(H, L) as u32 + 1) as u16);
             gb_cpu.clk += 4;
         }
@@ -644,10 +706,10 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x31 => // LD SP,nnnn - 3
         {
             gb_cpu.clk += 4;
-            let tmp = gb_mem.read(gb_cpu.pc);
+            let tmp = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
             gb_cpu.clk += 4;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
-            let tmp2 = gb_mem.read(gb_cpu.pc);
+            let tmp2 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
             gb_cpu.sp = (tmp2 as u16) << 8 | tmp as u16;
@@ -655,7 +717,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x32 => // LD [HL-],A - 2
         {
             gb_cpu.clk += 4;
-            gb_mem.write(gb_cpu.get_hilo(H, L), gb_cpu.regs[A]);
+            gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), gb_cpu.regs[A]);
             gb_cpu.set_hilo(H, L, (gb_cpu.get_hilo(H, L) as i32 - 1) as u16);
             gb_cpu.clk += 4;
         }
@@ -667,34 +729,34 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x34 => // INC [HL] - 3
         {
             gb_cpu.clk += 4;
-            let mut tmp: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+            let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N);
             gb_cpu.set_flag((tmp & 0xF) == 0xF, FL_H);
             tmp = (tmp + 1) & 0xFF;
             gb_cpu.set_flag(tmp == 0, FL_Z);
-            gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+            gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
             gb_cpu.clk += 4;
         }
         0x35 => // DEC [HL] - 3
         {
             gb_cpu.clk += 4;
-            let mut tmp: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+            let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
             gb_cpu.clk += 4;
             gb_cpu.set_flag(true, FL_N);
             gb_cpu.set_flag((tmp & 0xF) == 0x0, FL_H);
             tmp = tmp.wrapping_sub(1);
             gb_cpu.set_flag(tmp == 0, FL_Z);
-            gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+            gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
             gb_cpu.clk += 4;
         }
         0x36 => // LD [HL],n - 3
         {
             gb_cpu.clk += 4;
-            let tmp: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
-            gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+            gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
             gb_cpu.clk += 4;
         }
         0x37 => // SCF - 1
@@ -717,7 +779,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x3A => // LD A,[HL-] - 2
         {
             gb_cpu.clk += 4;
-            gb_cpu.regs[A] = gb_mem.read(gb_cpu.get_hilo(H, L));
+            gb_cpu.regs[A] = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             gb_cpu.set_hilo(H, L, (gb_cpu.get_hilo(H, L) as i32 - 1) as u16);
             gb_cpu.clk += 4;
         }
@@ -1012,7 +1074,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N);
             let tmp: u32 = gb_cpu.regs[A] as u32;
-            let tmp2: u32 =  gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+            let tmp2: u32 =  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
             gb_cpu.set_flag(((tmp & 0xF) + (tmp2 & 0xF)) > 0xF, FL_H);
             gb_cpu.regs[A] = gb_cpu.regs[A].wrapping_add(tmp2 as u8);
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
@@ -1043,7 +1105,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x8E => // ADC A,[HL] - 2
         {
             gb_cpu.clk += 4;
-            let n = gb_mem.read(gb_cpu.get_hilo(H, L));
+            let n = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             gb_cpu.set_flag(false, FL_N);
             let c = gb_cpu.get_flag(FL_C) as u8;
             let h = ((gb_cpu.regs[A] & 0xF) + (n & 0xF) + c) & 0x10;
@@ -1082,7 +1144,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0x96 => // SUB A,[HL] - 2
         {
             gb_cpu.clk += 4;
-            let tmp: u32 =  gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+            let tmp: u32 =  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
             gb_cpu.regs[F] = FL_N;
             gb_cpu.set_flag((gb_cpu.regs[A] & 0xF) < (tmp & 0xF) as u8, FL_H);
             gb_cpu.set_flag(gb_cpu.regs[A] < tmp as u8, FL_C);
@@ -1110,7 +1172,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.sbc_a_8(L),
         0x9E => // SBC A,[HL] - 2
         {
-            let n = gb_mem.read(gb_cpu.get_hilo(H, L));
+            let n = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             let carr = gb_cpu.get_flag(FL_C) as u16;
             let c = (gb_cpu.regs[A] as u16) < n as u16 + carr;
             let h = ((gb_cpu.regs[A] & 0xF) as u16) < (n & 0xF) as u16 + carr;
@@ -1149,7 +1211,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(true, FL_H);
             gb_cpu.set_flag(false, FL_N | FL_C);
-            gb_cpu.regs[A] &=  gb_mem.read(gb_cpu.get_hilo(H, L));
+            gb_cpu.regs[A] &=  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
             gb_cpu.clk += 4;
         }
@@ -1176,7 +1238,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N | FL_C | FL_H);
-            gb_cpu.regs[A] ^=  gb_mem.read(gb_cpu.get_hilo(H, L));
+            gb_cpu.regs[A] ^=  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
             gb_cpu.clk += 4;
         }
@@ -1201,7 +1263,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N | FL_C | FL_H);
-            gb_cpu.regs[A] |=  gb_mem.read(gb_cpu.get_hilo(H, L));
+            gb_cpu.regs[A] |=  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L));
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
             gb_cpu.clk += 4;
         }
@@ -1227,7 +1289,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(true, FL_N);
-            let tmp: u32 =  gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+            let tmp: u32 =  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
             gb_cpu.set_flag((gb_cpu.regs[A] & 0xF) < (tmp & 0xF) as u8, FL_H);
             gb_cpu.set_flag((gb_cpu.regs[A] as u32) < tmp, FL_C);
             gb_cpu.set_flag(gb_cpu.regs[A] as u32 == tmp, FL_Z);
@@ -1258,7 +1320,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N);
             let tmp: u32 = gb_cpu.regs[A] as u32;
-            let tmp2: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let tmp2: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.set_flag(((tmp & 0xF) + (tmp2 & 0xF)) > 0xF, FL_H);
             gb_cpu.regs[A] = gb_cpu.regs[A].wrapping_add(tmp2 as u8);
@@ -1279,7 +1341,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xCB =>
         {
             gb_cpu.clk += 4;
-            opcode = gb_mem.read(gb_cpu.pc);
+            opcode = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
             gb_cpu.pc = (gb_cpu.pc as u32 + 1) as u16;
             //println!("CBOP: {:#X} PC: {:#X}", opcode, gb_cpu.pc);
 
@@ -1299,13 +1361,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x06 => // RLC [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp: u32 =  gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp: u32 =  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.set_flag(false, FL_N | FL_H);
                     gb_cpu.set_flag(tmp & 0x80 != 0, FL_C);
                     tmp = (tmp << 1) | gb_cpu.get_flag(FL_C) as u32;
                     gb_cpu.set_flag(tmp == 0, FL_Z);
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
                     gb_cpu.clk += 4;
                 }
                 0x07 => // RLC A - 2
@@ -1325,13 +1387,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x0E => // RRC [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp: u32 =  gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp: u32 =  gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.set_flag(false, FL_N | FL_H);
                     gb_cpu.set_flag(tmp & 0x01 != 0, FL_C);
                     tmp = (tmp >> 1) | ((gb_cpu.get_flag(FL_C) as u32) << 7);
                     gb_cpu.set_flag(tmp == 0, FL_Z);
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
                     gb_cpu.clk += 4;
                 }
                 0x0F => // RRC A - 2
@@ -1351,14 +1413,16 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x16 => // RL [HL] - 4
                 {
                     let add = gb_cpu.get_hilo(H, L);
-                    let c = gb_cpu.get_flag(FL_C) as u32;
+                    let c = gb_cpu.get_flag(FL_C) as u8;
+                    gb_cpu.clk += 4;
+                    let hlp = gb_mem.read_at(gb_cpu.clk, add);
+                    gb_cpu.clk += 4;
+                    let res = hlp << 1 | c;
                     gb_cpu.regs[F] = 0;
-                    let mut hlp = gb_mem.read(add) as u32;
                     gb_cpu.set_flag(hlp & 0x80 != 0, FL_C);
-                    gb_mem.write(add, ((hlp << 1) + c) as u8);
-                    hlp = gb_mem.read(add) as u32;
-                    gb_cpu.set_flag(hlp == 0, FL_Z);
-                    gb_cpu.clk += 12;
+                    gb_cpu.set_flag(res == 0, FL_Z);
+                    gb_mem.write_at(gb_cpu.clk, add, res);
+                    gb_cpu.clk += 4;
                 }
                 0x17 => // RL A - 2
                     gb_cpu.rl_8(A),
@@ -1377,14 +1441,14 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x1E => // RR [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp2: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp2: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.set_flag(false, FL_N | FL_H);
                     let tmp: u32 = gb_cpu.get_flag(FL_C) as u32; // Old carry flag
                     gb_cpu.set_flag(tmp2 & 0x01 != 0, FL_C);
                     tmp2 = (tmp2 >> 1) | (tmp << 7);
                     gb_cpu.set_flag(tmp2 == 0, FL_Z);
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp2 as u8);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp2 as u8);
                     gb_cpu.clk += 4;
                 }
                 0x1F => // RR A - 2
@@ -1404,13 +1468,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x26 => // SLA [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.regs[F] = 0;
                     gb_cpu.set_flag(tmp & 0x80 != 0, FL_C);
-                    tmp = tmp << 1;
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
-                    gb_cpu.set_flag(gb_mem.read(gb_cpu.get_hilo(H, L)) == 0, FL_Z);
+                    tmp = (tmp << 1) & 0xFF;
+                    gb_cpu.set_flag(tmp == 0, FL_Z);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
                     gb_cpu.clk += 4;
                 }
                 0x27 => // SLA A - 2
@@ -1430,13 +1494,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x2E => // SRA [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.set_flag(false, FL_N | FL_H);
                     gb_cpu.set_flag(tmp & 0x01 != 0, FL_C);
                     tmp = (tmp & 0x80) | (tmp >> 1);
                     gb_cpu.set_flag(tmp == 0, FL_Z);
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
                     gb_cpu.clk += 4;
                 }
                 0x2F => // SRA A - 2
@@ -1456,11 +1520,11 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x36 => // SWAP [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.set_flag(false, FL_N | FL_H | FL_C);
                     tmp = (tmp >> 4) | (tmp << 4);
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
                     gb_cpu.set_flag(tmp == 0, FL_Z);
                     gb_cpu.clk += 4;
                 }
@@ -1481,13 +1545,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
                 0x3E => // SRL [HL] - 4
                 {
                     gb_cpu.clk += 4;
-                    let mut tmp: u32 = gb_mem.read(gb_cpu.get_hilo(H, L)) as u32;
+                    let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.get_hilo(H, L)) as u32;
                     gb_cpu.clk += 4;
                     gb_cpu.set_flag(false, FL_N | FL_H);
                     gb_cpu.set_flag(tmp & 0x01 != 0, FL_C);
                     tmp = tmp >> 1;
                     gb_cpu.set_flag(tmp == 0, FL_Z);
-                    gb_mem.write(gb_cpu.get_hilo(H, L), tmp as u8);
+                    gb_mem.write_at(gb_cpu.clk, gb_cpu.get_hilo(H, L), tmp as u8);
                     gb_cpu.clk += 4;
                 }
                 0x3F => // SRL A - 2
@@ -1887,7 +1951,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N);
-            let tmp: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             let tmp2: u32 = gb_cpu.regs[A] as u32 + tmp + gb_cpu.get_flag(FL_C) as u32;
             gb_cpu.set_flag(((gb_cpu.regs[A] & 0xF) + (tmp & 0xF) as u8 + gb_cpu.get_flag(FL_C) as u8) > 0xF, FL_H);
@@ -1913,7 +1977,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xD6 => // SUB A,nn - 2
         {
             gb_cpu.clk += 4;
-            let tmp = gb_mem.read(gb_cpu.pc).wrapping_sub(0) as u32;
+            let tmp = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc).wrapping_sub(0) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.regs[F] = FL_N;
             gb_cpu.set_flag((gb_cpu.regs[A] & 0xF) < (tmp & 0xF) as u8, FL_H);
@@ -1928,16 +1992,18 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.ret(gb_mem, gb_cpu.get_flag(FL_C)),
         0xD9 => // RETI - 4
         {
+            let (site, sp) = (gb_cpu.pc.wrapping_sub(1), gb_cpu.sp);
             gb_cpu.clk += 4;
-            let mut tmp: u32 = gb_mem.read(gb_cpu.sp) as u32;
+            let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.sp) as u32;
             gb_cpu.clk += 4;
             gb_cpu.sp = (gb_cpu.sp as u32 + 1) as u16;
-            tmp |= (gb_mem.read(gb_cpu.sp) as u32) << 8;
+            tmp |= (gb_mem.read_at(gb_cpu.clk, gb_cpu.sp) as u32) << 8;
             gb_cpu.sp = (gb_cpu.sp as u32 + 1) as u16;
             gb_cpu.clk += 4;
             gb_cpu.pc = tmp as u16;
             gb_cpu.ime = true;
             gb_cpu.clk += 4;
+            gb_cpu.leave(site, sp);
         }
         0xDA => // JP C,nnnn - 4/3
             gb_cpu.jp_addr16(gb_mem, gb_cpu.get_flag(FL_C)),
@@ -1950,7 +2016,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xDE => // SBC A,nn - 2
         {
             gb_cpu.clk += 4;
-            let tmp2: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let tmp2: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             let tmp: u32 = (gb_cpu.regs[A] as u32).wrapping_sub(tmp2).wrapping_sub((gb_cpu.get_flag(FL_C)) as u32);
             gb_cpu.regs[F] = if tmp & !0xFF != 0 {
@@ -1971,10 +2037,10 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xE0 => // LD [0xFF00+nn],A - 3
         {
             gb_cpu.clk += 4;
-            let tmp: u32 = 0xFF00 + gb_mem.read(gb_cpu.pc) as u32;
+            let tmp: u32 = 0xFF00 + gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
-            gb_mem.write(tmp as u16, gb_cpu.regs[A]);
+            gb_mem.write_at(gb_cpu.clk, tmp as u16, gb_cpu.regs[A]);
             gb_cpu.clk += 4;
         }
         0xE1 => // POP HL - 3
@@ -1982,7 +2048,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xE2 => // LD [0xFF00+C],A - 2
         {
             gb_cpu.clk += 4;
-            gb_mem.write(0xFF00 + gb_cpu.regs[C] as u16, gb_cpu.regs[A]);
+            gb_mem.write_at(gb_cpu.clk, 0xFF00 + gb_cpu.regs[C] as u16, gb_cpu.regs[A]);
             gb_cpu.clk += 4;
         }
         0xE3 => // Undefined - *
@@ -1996,7 +2062,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N | FL_C);
             gb_cpu.set_flag(true, FL_H);
-            gb_cpu.regs[A] &= gb_mem.read(gb_cpu.pc);
+            gb_cpu.regs[A] &= gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
             gb_cpu.clk += 4;
@@ -2007,7 +2073,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             // Expand sign
-            let tmp: u32 = gb_mem.read(gb_cpu.pc) as i8 as i16 as u16 as u32;
+            let tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as i8 as i16 as u16 as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.regs[F] = 0;
             gb_cpu.set_flag((gb_cpu.sp & 0x00FF) + (tmp & 0x00FF) as u16 > 0x00FF, FL_C);
@@ -2023,13 +2089,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xEA => // LD [nnnn],A - 4
         {
             gb_cpu.clk += 4;
-            let mut tmp: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.clk += 4;
             gb_cpu.pc = (gb_cpu.pc as u32 + 1) as u16;
-            tmp |= (gb_mem.read(gb_cpu.pc) as u32) << 8;
+            tmp |= (gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32) << 8;
             gb_cpu.pc = (gb_cpu.pc as u32 + 1) as u16;
             gb_cpu.clk += 4;
-            gb_mem.write(tmp as u16, gb_cpu.regs[A]);
+            gb_mem.write_at(gb_cpu.clk, tmp as u16, gb_cpu.regs[A]);
             gb_cpu.clk += 4;
         }
         0xEB => // Undefined - *
@@ -2042,7 +2108,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N | FL_C | FL_H);
-            gb_cpu.regs[A] ^= gb_mem.read(gb_cpu.pc);
+            gb_cpu.regs[A] ^= gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
             gb_cpu.clk += 4;
@@ -2053,10 +2119,10 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xF0 => // LD A,[0xFF00+nn] - 3
         {
             gb_cpu.clk += 4;
-            let tmp: u32 = 0xFF00 + gb_mem.read(gb_cpu.pc) as u32;
+            let tmp: u32 = 0xFF00 + gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
-            gb_cpu.regs[A] = gb_mem.read(tmp as u16);
+            gb_cpu.regs[A] = gb_mem.read_at(gb_cpu.clk, tmp as u16);
             gb_cpu.clk += 4;
         }
         0xF1 => // POP AF - 3
@@ -2067,7 +2133,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xF2 => // LD A,[0xFF00+C] - 2
         {
             gb_cpu.clk += 4;
-            gb_cpu.regs[A] = gb_mem.read(0xFF00 + gb_cpu.regs[C] as u16);
+            gb_cpu.regs[A] = gb_mem.read_at(gb_cpu.clk, 0xFF00 + gb_cpu.regs[C] as u16);
             gb_cpu.clk += 4;
         }
         0xF3 => // DI - 1
@@ -2083,7 +2149,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(false, FL_N | FL_C | FL_H);
-            gb_cpu.regs[A] |= gb_mem.read(gb_cpu.pc);
+            gb_cpu.regs[A] |= gb_mem.read_at(gb_cpu.clk, gb_cpu.pc);
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.set_flag(gb_cpu.regs[A] == 0, FL_Z);
             gb_cpu.clk += 4;
@@ -2093,7 +2159,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xF8 => // LD HL,SP+nn - 3
         {
             gb_cpu.clk += 4;
-            let tmp: i32 = gb_mem.read(gb_cpu.pc) as i8 as i32;
+            let tmp: i32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as i8 as i32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             let res = gb_cpu.sp as i32 + tmp;
             gb_cpu.set_hilo(H, L, res as u16);
@@ -2110,13 +2176,13 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         0xFA => // LD A,[nnnn] - 4
         {
             gb_cpu.clk += 4;
-            let mut tmp: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let mut tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.clk += 4;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
-            tmp |= (gb_mem.read(gb_cpu.pc) as u32) << 8;
+            tmp |= (gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32) << 8;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             gb_cpu.clk += 4;
-            gb_cpu.regs[A] = gb_mem.read(tmp as u16);
+            gb_cpu.regs[A] = gb_mem.read_at(gb_cpu.clk, tmp as u16);
             gb_cpu.clk += 4;
         }
         0xFB => // EI - 1
@@ -2132,7 +2198,7 @@ pub fn cpu_cycle(gb_cpu: &mut Cpu, gb_mem: &mut mem::Mem) {
         {
             gb_cpu.clk += 4;
             gb_cpu.set_flag(true, FL_N);
-            let tmp: u32 = gb_mem.read(gb_cpu.pc) as u32;
+            let tmp: u32 = gb_mem.read_at(gb_cpu.clk, gb_cpu.pc) as u32;
             gb_cpu.pc = gb_cpu.pc.wrapping_add(1);
             let tmp2: u32 = gb_cpu.regs[A] as u32;
             gb_cpu.set_flag((tmp2 & 0xF) < (tmp & 0xF), FL_H);
@@ -0,0 +1,695 @@
+use crate::asm;
+use crate::consts::*;
+use crate::cpu::{Cause, Cpu};
+use crate::disasm;
+use crate::gb::Gb;
+use crate::rewind;
+use crate::symbols::Symbols;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+s, step [n]             step n instructions
+n, next                 step over calls
+fin, finish             run until the current function returns
+c, continue             run until a breakpoint
+u, until <addr>         run to addr
+rs, rstep [n]           step back n instructions
+rc, rcontinue           run backwards to the previous breakpoint hit
+b, break <loc> [if <cond>]
+                        break at [bank:]addr or a symbol, optionally only when cond != 0
+d, delete [id]          delete a breakpoint, or all of them
+bl, breaks              list breakpoints
+bt, backtrace           show the call stack
+r, regs                 dump registers
+x <addr> [len]          dump memory
+l, list [addr] [n]      disassemble around addr (default pc)
+p, print <expr>         evaluate an expression
+a <addr> <instr>[; ...] assemble into memory, rom included
+ips <file>              save this session's rom patches as an IPS file
+q, quit                 quit the emulator
+
+Numbers are hex ($ and 0x prefixes optional), #n is decimal.
+Expressions: registers a f b c d e h l af bc de hl sp pc, symbols from the
+.sym file next to the rom (these win over bare hex), [addr] reads a byte,
+operators ! + - & | ^ == != < <= > >= && ||
+An empty line repeats the last command.";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Reg {
+    R8(usize),
+    R16(usize, usize),
+    Sp,
+    Pc,
+}
+
+fn reg(name: &str) -> Option<Reg> {
+    return Some(match name {
+        "a" => Reg::R8(A),
+        "f" => Reg::R8(F),
+        "b" => Reg::R8(B),
+        "c" => Reg::R8(C),
+        "d" => Reg::R8(D),
+        "e" => Reg::R8(E),
+        "h" => Reg::R8(H),
+        "l" => Reg::R8(L),
+        "af" => Reg::R16(A, F),
+        "bc" => Reg::R16(B, C),
+        "de" => Reg::R16(D, E),
+        "hl" => Reg::R16(H, L),
+        "sp" => Reg::Sp,
+        "pc" => Reg::Pc,
+        _ => return None
+    });
+}
+
+pub fn parse_num(s: &str) -> Option<u32> {
+    return if let Some(d) = s.strip_prefix('#') {
+        d.parse().ok()
+    } else {
+        let h = s.strip_prefix('$').or(s.strip_prefix("0x")).unwrap_or(s);
+        u32::from_str_radix(h, 16).ok()
+    };
+}
+
+// Breakpoint conditions and command arguments
+pub enum Expr {
+    Num(u32),
+    Reg(Reg),
+    Mem(Box<Expr>),
+    Not(Box<Expr>),
+    Bin(&'static str, Box<Expr>, Box<Expr>),
+}
+
+const BIN_OPS: [(&str, u8); 14] = [
+    ("||", 1), ("&&", 2),
+    ("==", 3), ("!=", 3), ("<=", 3), (">=", 3), ("<", 3), (">", 3),
+    ("|", 4), ("^", 5), ("&", 6), ("+", 7), ("-", 7), ("!", 0),
+];
+
+#[derive(PartialEq)]
+enum Tok {
+    Word(String),
+    Op(&'static str),
+    Open(char),
+    Close(char),
+}
+
+fn lex(s: &str) -> Result<Vec<Tok>, String> {
+    let cs: Vec<char> = s.chars().collect();
+    let mut toks = Vec::new();
+    let mut i = 0;
+    while i < cs.len() {
+        let c = cs[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' || c == '[' {
+            toks.push(Tok::Open(c));
+            i += 1;
+        } else if c == ')' || c == ']' {
+            toks.push(Tok::Close(c));
+            i += 1;
+        } else if c.is_alphanumeric() || c == '$' || c == '#' || c == '_' || c == '.' {
+            let st = i;
+            i += 1;
+            while i < cs.len() && (cs[i].is_alphanumeric() || cs[i] == '_' || cs[i] == '.') {
+                i += 1;
+            }
+            toks.push(Tok::Word(cs[st..i].iter().collect()));
+        } else {
+            let rest: String = cs[i..].iter().take(2).collect();
+            match BIN_OPS.iter().find(|(o, _)| rest.starts_with(o)) {
+                Some((o, _)) => {
+                    toks.push(Tok::Op(o));
+                    i += o.len();
+                }
+                None => return Err(format!("unexpected '{}'", c))
+            }
+        }
+    }
+    return Ok(toks);
+}
+
+struct Parser<'a> {
+    toks: Vec<Tok>,
+    pos: usize,
+    syms: &'a Symbols,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<&Tok> {
+        self.pos += 1;
+        return self.toks.get(self.pos - 1);
+    }
+
+    fn binary(&mut self, min: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Tok::Op(o)) = self.toks.get(self.pos) {
+            let o: &'static str = o;
+            let prec = BIN_OPS.iter().find(|(b, _)| *b == o).unwrap().1;
+            if prec == 0 || prec < min {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(prec + 1)?;
+            lhs = Expr::Bin(o, Box::new(lhs), Box::new(rhs));
+        }
+        return Ok(lhs);
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let syms = self.syms;
+        return match self.next() {
+            Some(Tok::Op("!")) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Tok::Open(c)) => {
+                let mem = *c == '[';
+                let e = self.binary(1)?;
+                match self.next() {
+                    Some(Tok::Close(d)) if (*d == ']') == mem => Ok(if mem { Expr::Mem(Box::new(e)) } else { e }),
+                    _ => Err("unbalanced brackets".to_string())
+                }
+            }
+            Some(Tok::Word(w)) => {
+                let lw = w.to_lowercase();
+                if let Some(r) = reg(&lw) {
+                    Ok(Expr::Reg(r))
+                } else if let Some((_, addr)) = syms.lookup(w) {
+                    Ok(Expr::Num(addr as u32))
+                } else if let Some(n) = parse_num(&lw) {
+                    Ok(Expr::Num(n))
+                } else {
+                    Err(format!("unknown symbol {}", w))
+                }
+            }
+            _ => Err("expected a value".to_string())
+        };
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str, syms: &Symbols) -> Result<Expr, String> {
+        let mut p = Parser { toks: lex(s)?, pos: 0, syms };
+        let e = p.binary(1)?;
+        if p.pos != p.toks.len() {
+            return Err("trailing input".to_string());
+        }
+        return Ok(e);
+    }
+
+    pub fn eval(&self, gb: &Gb) -> u32 {
+        return match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(Reg::R8(r)) => gb.cpu.regs[*r] as u32,
+            Expr::Reg(Reg::R16(h, l)) => gb.cpu.get_hilo(*h, *l) as u32,
+            Expr::Reg(Reg::Sp) => gb.cpu.sp as u32,
+            Expr::Reg(Reg::Pc) => gb.cpu.pc as u32,
+            Expr::Mem(a) => gb.mem.read(a.eval(gb) as u16) as u32,
+            Expr::Not(e) => (e.eval(gb) == 0) as u32,
+            Expr::Bin(o, l, r) => {
+                let (l, r) = (l.eval(gb), r.eval(gb));
+                match *o {
+                    "||" => (l != 0 || r != 0) as u32,
+                    "&&" => (l != 0 && r != 0) as u32,
+                    "==" => (l == r) as u32,
+                    "!=" => (l != r) as u32,
+                    "<=" => (l <= r) as u32,
+                    ">=" => (l >= r) as u32,
+                    "<" => (l < r) as u32,
+                    ">" => (l > r) as u32,
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "&" => l & r,
+                    "+" => l.wrapping_add(r),
+                    _ => l.wrapping_sub(r),
+                }
+            }
+        };
+    }
+}
+
+// Offset 0x454F46 spells EOF, a record there would end the patch
+const IPS_EOF: usize = 0x454F46;
+
+// PATCH, then offset (3 bytes), length (2 bytes) and data per run of bytes, then EOF.
+// rom gives the unpatched bytes, for moving a run off IPS_EOF
+fn ips(patches: &BTreeMap<usize, u8>, rom: &dyn Fn(usize) -> u8) -> Vec<u8> {
+    let mut out = b"PATCH".to_vec();
+    let mut it = patches.iter().peekable();
+    while let Some((&off, &b)) = it.next() {
+        let (mut off, mut run) = (off, vec![b]);
+        if off == IPS_EOF {
+            off -= 1;
+            run.insert(0, patches.get(&off).cloned().unwrap_or_else(|| rom(off)));
+        }
+        while let Some((&o, &v)) = it.peek() {
+            if o != off + run.len() || run.len() == 0xFFFF {
+                break;
+            }
+            run.push(v);
+            it.next();
+        }
+        out.extend_from_slice(&(off as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(&(run.len() as u16).to_be_bytes());
+        out.extend_from_slice(&run);
+    }
+    out.extend_from_slice(b"EOF");
+    return out;
+}
+
+struct Breakpoint {
+    id: u32,
+    bank: Option<u16>,
+    addr: u16,
+    cond: Option<(String, Expr)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Run {
+    Cont,
+    Step(u32),
+    Over(u16, u16), // return addr, sp
+    Out(u16), // sp
+    To(u16),
+}
+
+pub struct Debugger {
+    breaks: Vec<Breakpoint>,
+    next_id: u32,
+    run: Run,
+    brk: bool, // stop before the next instruction
+    ret_op: bool, // last instruction was a RET
+    last: String,
+    patches: BTreeMap<usize, u8>, // rom offset, value
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger {
+            breaks: Vec::new(),
+            next_id: 1,
+            run: Run::Cont,
+            brk: false,
+            ret_op: false,
+            last: String::new(),
+            patches: BTreeMap::new(),
+        }
+    }
+}
+
+fn flags(cpu: &Cpu) -> String {
+    return [(FL_Z, 'Z'), (FL_N, 'N'), (FL_H, 'H'), (FL_C, 'C')].iter()
+        .map(|(f, c)| if cpu.get_flag(*f) { *c } else { '-' })
+        .collect();
+}
+
+impl Debugger {
+    // Whether check needs to be called at all
+    pub fn active(&self) -> bool {
+        return self.brk || self.run != Run::Cont || !self.breaks.is_empty();
+    }
+
+    pub fn request_break(&mut self) {
+        self.brk = true;
+    }
+
+    // Called before every instruction but the one we stopped at, true if we should drop into the repl
+    pub fn check(&mut self, gb: &Gb) -> bool {
+        let pc = gb.cpu.pc;
+        let stop = self.brk || match self.run {
+            Run::Cont => false,
+            Run::Step(n) => {
+                self.run = Run::Step(n.saturating_sub(1));
+                n <= 1
+            }
+            Run::Over(ret, sp) => pc == ret && gb.cpu.sp >= sp,
+            Run::Out(sp) => self.ret_op && gb.cpu.sp > sp,
+            Run::To(addr) => pc == addr,
+        } || self.hit(gb);
+
+        if let Run::Out(_) = self.run {
+            self.ret_op = disasm::decode(|a| gb.mem.read(a), pc).is_ret();
+        }
+        if stop {
+            self.brk = false;
+            self.run = Run::Cont;
+        }
+        return stop;
+    }
+
+    fn hit(&self, gb: &Gb) -> bool {
+        let pc = gb.cpu.pc;
+        return self.breaks.iter().any(|b| {
+            b.addr == pc
+                && b.bank.is_none_or(|bk| bk == gb.mem.bank_of(pc))
+                && b.cond.as_ref().is_none_or(|(_, c)| c.eval(gb) != 0)
+        });
+    }
+
+    fn arg(&self, gb: &Gb, s: &str) -> Result<u16, String> {
+        return Ok(Expr::parse(s, &gb.syms)?.eval(gb) as u16);
+    }
+
+    fn loc(&self, gb: &Gb, addr: u16) -> String {
+        return format!("{:02X}:{:04X}", gb.mem.bank_of(addr), addr);
+    }
+
+    fn regs(&self, gb: &Gb) {
+        let c = &gb.cpu;
+        println!("AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} {} IME={} HALT={} LY={:02X} CLK={}",
+            c.get_hilo(A, F), c.get_hilo(B, C), c.get_hilo(D, E), c.get_hilo(H, L), c.sp, c.pc,
+            flags(c), c.ime as u8, c.halt, gb.mem.read(SCLINEP), c.clk);
+    }
+
+    fn print_instr(&self, gb: &Gb, addr: u16) -> u16 {
+        let ins = disasm::decode(|a| gb.mem.read(a), addr);
+        let bytes: Vec<String> = (0..ins.len).map(|i| format!("{:02X}", gb.mem.read(addr.wrapping_add(i)))).collect();
+        let mark = if addr == gb.cpu.pc { "=>" } else { "  " };
+        if let Some(l) = gb.syms.label(&gb.mem, addr) {
+            println!("{}:", l);
+        }
+        println!("{} {}  {:<9} {}", mark, self.loc(gb, addr), bytes.join(" "), ins.text_with(&|a| gb.syms.label(&gb.mem, a)));
+        return ins.len;
+    }
+
+    fn list(&self, gb: &Gb, at: u16, n: usize) {
+        // Find a start a few instructions back that decodes right into at
+        let before = n / 3;
+        let mut start = at;
+        for back in (1..=before as u16 * 3).rev() {
+            let mut addrs = Vec::new();
+            let mut a = at.wrapping_sub(back);
+            while a < at && addrs.len() <= back as usize {
+                addrs.push(a);
+                a = a.wrapping_add(disasm::decode(|x| gb.mem.read(x), a).len);
+            }
+            if a == at {
+                start = addrs[addrs.len().saturating_sub(before)];
+                break;
+            }
+        }
+
+        let mut addr = start;
+        for _ in 0..n {
+            addr = addr.wrapping_add(self.print_instr(gb, addr));
+        }
+    }
+
+    fn backtrace(&self, gb: &Gb) {
+        println!("#0  {}", gb.loc(gb.cpu.pc));
+        for (i, f) in gb.cpu.calls.iter().rev().enumerate() {
+            let how = match f.cause {
+                Cause::Call => "CALL".to_string(),
+                Cause::Rst => "RST".to_string(),
+                Cause::Int(n) => format!("interrupt {}", n),
+            };
+            let to = gb.syms.name(f.bank, f.target).map_or(format!("{:02X}:{:04X}", f.bank, f.target), |s| s.to_string());
+            println!("#{}  {}  {} {}", i + 1, gb.loc(f.site), how, to);
+        }
+    }
+
+    fn assemble(&mut self, gb: &mut Gb, args: &str) -> Result<(), String> {
+        let (at, src) = args.split_at(args.find(char::is_whitespace).ok_or("a <addr> <instr>[; ...]")?);
+        let start = self.arg(gb, at)?;
+        let mut addr = start;
+        for line in src.split(';') {
+            let bytes = asm::assemble(line, addr, &|s| Ok(Expr::parse(s, &gb.syms)?.eval(gb)))?;
+            for b in bytes {
+                if let Some(off) = gb.mem.poke(addr, b) {
+                    self.patches.insert(off, b);
+                }
+                addr = addr.wrapping_add(1);
+            }
+        }
+
+        let mut a = start;
+        while a != addr {
+            a = a.wrapping_add(self.print_instr(gb, a));
+        }
+        return Ok(());
+    }
+
+    fn save_ips(&self, gb: &Gb, path: &str) -> Result<(), String> {
+        fs::write(path, ips(&self.patches, &|off| gb.mem.rom_byte(off))).map_err(|e| format!("{}: {}", path, e))?;
+        println!("{} patched bytes written to {}", self.patches.len(), path);
+        return Ok(());
+    }
+
+    fn dump(&self, gb: &Gb, addr: u16, len: u16) {
+        for row in (0..len).step_by(16) {
+            let base = addr.wrapping_add(row);
+            let bytes: Vec<u8> = (0..16.min(len - row)).map(|i| gb.mem.read(base.wrapping_add(i))).collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let asc: String = bytes.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }).collect();
+            println!("{:04X}: {:<47}  {}", base, hex.join(" "), asc);
+        }
+    }
+
+    fn add_break(&mut self, gb: &Gb, args: &[&str]) -> Result<(), String> {
+        let loc = args.first().ok_or("break <[bank:]addr|symbol> [if <cond>]")?;
+        let (bank, addr) = match (gb.syms.lookup(loc), loc.find(':')) {
+            (Some((bank, addr)), _) => (Some(bank), addr),
+            (None, Some(i)) => (Some(parse_num(&loc[..i]).ok_or("bad bank")? as u16), self.arg(gb, &loc[i + 1..])?),
+            (None, None) => (None, self.arg(gb, loc)?)
+        };
+        let cond = match args.get(1) {
+            Some(&"if") => {
+                let src = args[2..].join(" ");
+                let e = Expr::parse(&src, &gb.syms)?;
+                Some((src, e))
+            }
+            Some(_) => return Err("expected 'if'".to_string()),
+            None => None
+        };
+
+        println!("breakpoint {} at {}", self.next_id, self.fmt_break(gb, bank, addr, &cond));
+        self.breaks.push(Breakpoint { id: self.next_id, bank, addr, cond });
+        self.next_id += 1;
+        return Ok(());
+    }
+
+    fn fmt_break(&self, gb: &Gb, bank: Option<u16>, addr: u16, cond: &Option<(String, Expr)>) -> String {
+        let mut s = match bank {
+            Some(b) => format!("{:02X}:{:04X}", b, addr),
+            None => format!("{:04X}", addr),
+        };
+        if let Some(l) = gb.syms.near(bank.unwrap_or(gb.mem.bank_of(addr)), addr) {
+            s += &format!(" <{}>", l);
+        }
+        if let Some((src, _)) = cond {
+            s += &format!(" if {}", src);
+        }
+        return s;
+    }
+
+    // Returns true when execution should resume
+    fn command(&mut self, gb: &mut Gb, line: &str) -> Result<bool, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let cmd = match args.first() {
+            Some(c) => *c,
+            None => return Ok(false)
+        };
+        let rest = &args[1..];
+
+        match cmd {
+            "s" | "step" => {
+                let n = match rest.first() { Some(n) => parse_num(n).ok_or("bad count")?, None => 1 };
+                self.run = Run::Step(n.max(1));
+                return Ok(true);
+            }
+            "n" | "next" => {
+                let ins = disasm::decode(|a| gb.mem.read(a), gb.cpu.pc);
+                self.run = if ins.is_call() {
+                    Run::Over(gb.cpu.pc.wrapping_add(ins.len), gb.cpu.sp)
+                } else {
+                    Run::Step(1)
+                };
+                return Ok(true);
+            }
+            "fin" | "finish" => {
+                self.run = Run::Out(gb.cpu.sp);
+                self.ret_op = false;
+                return Ok(true);
+            }
+            "c" | "continue" => return Ok(true),
+            "u" | "until" => {
+                self.run = Run::To(self.arg(gb, rest.first().ok_or("until <addr>")?)?);
+                return Ok(true);
+            }
+            "rs" | "rstep" => {
+                let n = match rest.first() { Some(n) => parse_num(n).ok_or("bad count")?, None => 1 };
+                if !rewind::seek(gb, gb.hist.steps.saturating_sub(n as u64)) {
+                    return Err(format!("no history that far back, oldest is {} steps ago", gb.hist.steps - gb.hist.start()));
+                }
+                self.print_instr(gb, gb.cpu.pc);
+            }
+            "rc" | "rcontinue" => {
+                if !gb.hist.enabled() {
+                    return Err("rewind is disabled".to_string());
+                }
+                if !rewind::reverse_until(gb, &|g| self.hit(g)) {
+                    println!("reached the start of history");
+                }
+                self.print_instr(gb, gb.cpu.pc);
+            }
+            "b" | "break" => self.add_break(gb, rest)?,
+            "d" | "delete" => match rest.first() {
+                Some(id) => {
+                    let id = parse_num(id).ok_or("bad id")?;
+                    let n = self.breaks.len();
+                    self.breaks.retain(|b| b.id != id);
+                    if n == self.breaks.len() {
+                        return Err(format!("no breakpoint {}", id));
+                    }
+                }
+                None => self.breaks.clear(),
+            },
+            "bl" | "breaks" => {
+                for b in self.breaks.iter() {
+                    println!("{}: {}", b.id, self.fmt_break(gb, b.bank, b.addr, &b.cond));
+                }
+            }
+            "bt" | "backtrace" => self.backtrace(gb),
+            "r" | "regs" => self.regs(gb),
+            "x" => {
+                let addr = self.arg(gb, rest.first().ok_or("x <addr> [len]")?)?;
+                let len = match rest.get(1) { Some(l) => parse_num(l).ok_or("bad length")? as u16, None => 0x40 };
+                self.dump(gb, addr, len);
+            }
+            "l" | "list" => {
+                let addr = match rest.first() { Some(a) => self.arg(gb, a)?, None => gb.cpu.pc };
+                let n = match rest.get(1) { Some(n) => parse_num(n).ok_or("bad count")? as usize, None => 12 };
+                self.list(gb, addr, n);
+            }
+            "p" | "print" => {
+                let v = Expr::parse(&rest.join(" "), &gb.syms)?.eval(gb);
+                println!("${:X} #{}", v, v);
+            }
+            "a" => self.assemble(gb, line[1..].trim())?,
+            "ips" => self.save_ips(gb, rest.first().ok_or("ips <file>")?)?,
+            "q" | "quit" => {
+                gb.cpu.stop = 1;
+                return Ok(true);
+            }
+            "h" | "help" => println!("{}", HELP),
+            _ => return Err(format!("unknown command {}, try help", cmd))
+        }
+        return Ok(false);
+    }
+
+    pub fn repl(&mut self, gb: &mut Gb) {
+        self.print_instr(gb, gb.cpu.pc);
+        let stdin = io::stdin();
+        loop {
+            print!("(gb) ");
+            io::stdout().flush().unwrap_or_default();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                gb.cpu.stop = 1;
+                return;
+            }
+            let line = match line.trim() {
+                "" => self.last.clone(),
+                l => l.to_string()
+            };
+            self.last = line.clone();
+
+            match self.command(gb, &line) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, gb: &Gb) -> u32 {
+        return Expr::parse(s, &Symbols::default()).unwrap().eval(gb);
+    }
+
+    #[test]
+    fn precedence() {
+        let gb = Gb::default();
+        assert_eq!(eval("1 + 2 & 3", &gb), 3); // (1+2)&3
+        assert_eq!(eval("1 | 2 == 3", &gb), 1); // (1|2)==3
+        assert_eq!(eval("6 ^ 3 | 8", &gb), 13); // (6^3)|8
+        assert_eq!(eval("4 & 6 ^ 1", &gb), 5); // (4&6)^1
+        assert_eq!(eval("0 || 1 && 0", &gb), 0); // 0||(1&&0)
+        assert_eq!(eval("#10 - 3 - 2", &gb), 5); // left to right
+        assert_eq!(eval("!0 + 1", &gb), 2);
+        assert_eq!(eval("(1 | 2) & 2", &gb), 2);
+        assert_eq!(eval("#10 == $A", &gb), 1);
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut gb = Gb::default();
+        gb.cpu.set_hilo(H, L, 0xC000);
+        gb.mem.write(0xC000, 0x42);
+        gb.mem.write(0xC042, 7);
+        assert_eq!(eval("hl", &gb), 0xC000);
+        assert_eq!(eval("H == $C0 && l == 0", &gb), 1);
+        assert_eq!(eval("[hl]", &gb), 0x42);
+        assert_eq!(eval("[$C000 + [hl]]", &gb), 7);
+        assert_eq!(eval("pc", &gb), 0x100);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let syms = Symbols::default();
+        for (s, want) in [
+            ("", "expected a value"),
+            ("1 +", "expected a value"),
+            ("[hl", "unbalanced brackets"),
+            ("(1 + 2]", "unbalanced brackets"),
+            ("1 2", "trailing input"),
+            ("nolabel", "unknown symbol nolabel"),
+            ("1 * 2", "unexpected '*'"),
+        ] {
+            match Expr::parse(s, &syms) {
+                Ok(_) => panic!("{} parsed", s),
+                Err(e) => assert!(e.contains(want), "{}: {}", s, e),
+            }
+        }
+    }
+
+    // Records as (offset, data)
+    fn records(ips: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        assert_eq!(&ips[..5], b"PATCH");
+        let mut recs = Vec::new();
+        let mut i = 5;
+        while &ips[i..i + 3] != b"EOF" {
+            let off = (ips[i] as usize) << 16 | (ips[i + 1] as usize) << 8 | ips[i + 2] as usize;
+            let len = (ips[i + 3] as usize) << 8 | ips[i + 4] as usize;
+            recs.push((off, ips[i + 5..i + 5 + len].to_vec()));
+            i += 5 + len;
+        }
+        assert_eq!(i + 3, ips.len());
+        return recs;
+    }
+
+    #[test]
+    fn ips_runs() {
+        let patches = BTreeMap::from([(0x100, 1), (0x101, 2), (0x200, 3)]);
+        let recs = records(&ips(&patches, &|_| 0xEE));
+        assert_eq!(recs, vec![(0x100, vec![1, 2]), (0x200, vec![3])]);
+    }
+
+    #[test]
+    fn ips_eof_offset() {
+        // A run at 0x454F46 starts a byte early with the rom's byte
+        let patches = BTreeMap::from([(IPS_EOF, 1), (IPS_EOF + 1, 2)]);
+        let recs = records(&ips(&patches, &|off| off as u8));
+        assert_eq!(recs, vec![(IPS_EOF - 1, vec![0x45, 1, 2])]);
+
+        // Running into it from before is fine as it is
+        let patches = BTreeMap::from([(IPS_EOF - 1, 9), (IPS_EOF, 1)]);
+        let recs = records(&ips(&patches, &|_| 0xEE));
+        assert_eq!(recs, vec![(IPS_EOF - 1, vec![9, 1])]);
+    }
+}
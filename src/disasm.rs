@@ -0,0 +1,158 @@
+// SM83 instruction decoding, RGBDS syntax
+//
+// Operand placeholders in the templates:
+// n8/n16 - immediates, a16 - absolute address, a8 - 0xFF00 page address,
+// e8 - relative jump target, s8 - signed SP offset
+
+const OPS: [&str; 256] = [
+    "NOP", "LD BC,n16", "LD [BC],A", "INC BC", "INC B", "DEC B", "LD B,n8", "RLCA",
+    "LD [a16],SP", "ADD HL,BC", "LD A,[BC]", "DEC BC", "INC C", "DEC C", "LD C,n8", "RRCA",
+    "STOP", "LD DE,n16", "LD [DE],A", "INC DE", "INC D", "DEC D", "LD D,n8", "RLA",
+    "JR e8", "ADD HL,DE", "LD A,[DE]", "DEC DE", "INC E", "DEC E", "LD E,n8", "RRA",
+    "JR NZ,e8", "LD HL,n16", "LD [HL+],A", "INC HL", "INC H", "DEC H", "LD H,n8", "DAA",
+    "JR Z,e8", "ADD HL,HL", "LD A,[HL+]", "DEC HL", "INC L", "DEC L", "LD L,n8", "CPL",
+    "JR NC,e8", "LD SP,n16", "LD [HL-],A", "INC SP", "INC [HL]", "DEC [HL]", "LD [HL],n8", "SCF",
+    "JR C,e8", "ADD HL,SP", "LD A,[HL-]", "DEC SP", "INC A", "DEC A", "LD A,n8", "CCF",
+    // 0x40 - 0xBF are generated
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "", "", "",
+    "RET NZ", "POP BC", "JP NZ,a16", "JP a16", "CALL NZ,a16", "PUSH BC", "ADD A,n8", "RST $00",
+    "RET Z", "RET", "JP Z,a16", "", "CALL Z,a16", "CALL a16", "ADC A,n8", "RST $08",
+    "RET NC", "POP DE", "JP NC,a16", "", "CALL NC,a16", "PUSH DE", "SUB A,n8", "RST $10",
+    "RET C", "RETI", "JP C,a16", "", "CALL C,a16", "", "SBC A,n8", "RST $18",
+    "LDH [a8],A", "POP HL", "LDH [C],A", "", "", "PUSH HL", "AND A,n8", "RST $20",
+    "ADD SP,s8", "JP HL", "LD [a16],A", "", "", "", "XOR A,n8", "RST $28",
+    "LDH A,[a8]", "POP AF", "LDH A,[C]", "DI", "", "PUSH AF", "OR A,n8", "RST $30",
+    "LD HL,SPs8", "LD SP,HL", "LD A,[a16]", "EI", "", "", "CP A,n8", "RST $38",
+];
+
+pub const REG8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "[HL]", "A"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB A,", "SBC A,", "AND A,", "XOR A,", "OR A,", "CP A,"];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// Template for an opcode, empty for undefined ones
+pub fn template(op: u8, cb: bool) -> String {
+    let r = REG8[(op & 7) as usize];
+    if cb {
+        let n = (op >> 3) & 7;
+        return match op >> 6 {
+            0 => format!("{} {}", ROT[n as usize], r),
+            1 => format!("BIT {},{}", n, r),
+            2 => format!("RES {},{}", n, r),
+            _ => format!("SET {},{}", n, r),
+        };
+    }
+
+    return match op {
+        0x76 => "HALT".to_string(),
+        0x40..=0x7F => format!("LD {},{}", REG8[((op >> 3) & 7) as usize], r),
+        0x80..=0xBF => format!("{}{}", ALU[((op >> 3) & 7) as usize], r),
+        _ => OPS[op as usize].to_string()
+    };
+}
+
+fn arg_len(tmpl: &str) -> u16 {
+    if tmpl.contains("n16") || tmpl.contains("a16") {
+        2
+    } else if tmpl.contains("n8") || tmpl.contains("a8") || tmpl.contains("e8") || tmpl.contains("s8") {
+        1
+    } else {
+        0
+    }
+}
+
+pub struct Instr {
+    pub addr: u16,
+    pub op: u8,
+    pub cb: bool,
+    pub len: u16,
+    pub arg: u16,
+}
+
+impl Instr {
+    pub fn template(&self) -> String {
+        return template(self.op, self.cb);
+    }
+
+    // Jump/call destination, if it is known statically
+    pub fn target(&self) -> Option<u16> {
+        if self.cb {
+            return None;
+        }
+        return match self.op {
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(self.addr.wrapping_add(2).wrapping_add(self.arg as i8 as u16)),
+            0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2 | 0xD4 | 0xDA | 0xDC => Some(self.arg),
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Some((self.op & 0x38) as u16),
+            _ => None
+        };
+    }
+
+    pub fn is_call(&self) -> bool {
+        return !self.cb && match self.op {
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => true,
+            op => op & 0xC7 == 0xC7,
+        };
+    }
+
+    pub fn is_ret(&self) -> bool {
+        return !self.cb && matches!(self.op, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9);
+    }
+
+    pub fn defined(&self) -> bool {
+        return !self.template().is_empty();
+    }
+
+    // Execution never falls through to the next instruction
+    pub fn is_end(&self) -> bool {
+        return !self.cb && matches!(self.op, 0x18 | 0xC3 | 0xC9 | 0xD9 | 0xE9);
+    }
+
+    // Render with `name` used to turn addresses into labels
+    pub fn text_with(&self, name: &dyn Fn(u16) -> Option<String>) -> String {
+        let tmpl = self.template();
+        if tmpl.is_empty() {
+            return format!("DB ${:02X}", self.op);
+        }
+
+        let addr = |a: u16| name(a).unwrap_or(format!("${:04X}", a));
+        return if tmpl.contains("n16") {
+            tmpl.replace("n16", &format!("${:04X}", self.arg))
+        } else if tmpl.contains("a16") {
+            tmpl.replace("a16", &addr(self.arg))
+        } else if tmpl.contains("n8") {
+            tmpl.replace("n8", &format!("${:02X}", self.arg))
+        } else if tmpl.contains("a8") {
+            tmpl.replace("a8", &addr(0xFF00 | self.arg))
+        } else if tmpl.contains("e8") {
+            tmpl.replace("e8", &addr(self.target().unwrap()))
+        } else if tmpl.contains("s8") {
+            let off = self.arg as u8 as i8;
+            let s = if off < 0 { format!("-${:02X}", -(off as i16)) } else { format!("+${:02X}", off) };
+            tmpl.replace("s8", &s)
+        } else {
+            tmpl
+        };
+    }
+}
+
+pub fn decode(read: impl Fn(u16) -> u8, addr: u16) -> Instr {
+    let mut op = read(addr);
+    let cb = op == 0xCB;
+    if cb {
+        op = read(addr.wrapping_add(1));
+        return Instr { addr, op, cb, len: 2, arg: 0 };
+    }
+
+    let alen = if op == 0x10 { 1 } else { arg_len(OPS[op as usize]) };
+    let mut arg = 0;
+    for i in 0..alen {
+        arg |= (read(addr.wrapping_add(1 + i)) as u16) << (8 * i);
+    }
+    return Instr { addr, op, cb, len: 1 + alen, arg };
+}
@@ -0,0 +1,73 @@
+// minifb has no sound, queued audio is dropped
+use crate::consts::*;
+use crate::gpu::rgb;
+use crate::input::KeyCode;
+use super::{Event, Frontend, Hotkey};
+use ::minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+pub struct MiniFb {
+    window: Window,
+    buf: Vec<u32>, // 0RGB
+}
+
+impl MiniFb {
+    pub fn new() -> Result<MiniFb, String> {
+        let scale = match SCALE {
+            1 => Scale::X1,
+            2 => Scale::X2,
+            4 => Scale::X4,
+            8 => Scale::X8,
+            _ => Scale::FitScreen
+        };
+        let opts = WindowOptions { scale, ..WindowOptions::default() };
+        let window = Window::new("Gameboy Emu", WIDTH, HEIGHT, opts).map_err(|e| e.to_string())?;
+        return Ok(MiniFb {
+            window,
+            buf: vec![0; WIDTH*HEIGHT],
+        });
+    }
+
+    fn input_key(key: Key) -> KeyCode {
+        match key {
+            Key::Enter => KeyCode::Start,
+            Key::Space => KeyCode::Select,
+            Key::E => KeyCode::B,
+            Key::Q => KeyCode::A,
+            Key::S => KeyCode::Down,
+            Key::W => KeyCode::Up,
+            Key::A => KeyCode::Left,
+            Key::D => KeyCode::Right,
+            _ => KeyCode::Uk
+        }
+    }
+}
+
+impl Frontend for MiniFb {
+    fn present(&mut self, frame: &[u16]) {
+        for (p, pix) in self.buf.iter_mut().zip(frame) {
+            let (r, g, b) = rgb(*pix);
+            *p = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        }
+        self.window.update_with_buffer(&self.buf, WIDTH, HEIGHT).unwrap_or_default();
+    }
+
+    fn poll(&mut self) -> Vec<Event> {
+        let mut evs = Vec::new();
+        if !self.window.is_open() {
+            evs.push(Event::Quit);
+        }
+        for k in self.window.get_keys_pressed(KeyRepeat::No).unwrap_or_default() {
+            evs.push(match k {
+                Key::Escape => Event::Quit,
+                Key::F12 => Event::Hotkey(Hotkey::Debug),
+                Key::F11 => Event::Hotkey(Hotkey::Screenshot),
+                Key::F10 => Event::Hotkey(Hotkey::Record),
+                _ => Event::Key(MiniFb::input_key(k), true),
+            });
+        }
+        for k in self.window.get_keys_released().unwrap_or_default() {
+            evs.push(Event::Key(MiniFb::input_key(k), false));
+        }
+        return evs;
+    }
+}
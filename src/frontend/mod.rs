@@ -0,0 +1,55 @@
+// Window, keyboard and sound backends, each behind a cargo feature
+use crate::input::KeyCode;
+
+#[cfg(feature = "minifb")]
+mod minifb;
+#[cfg(feature = "sdl")]
+mod sdl;
+
+pub const SAMPLE_RATE: u32 = 48000; // stereo i16 samples, interleaved
+
+// Backends compiled in, the first is the default
+pub const BACKENDS: &[&str] = &[
+    #[cfg(feature = "sdl")]
+    "sdl",
+    #[cfg(feature = "minifb")]
+    "minifb",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(not(any(feature = "sdl", feature = "minifb")), allow(dead_code))] // only frontends make these
+pub enum Hotkey {
+    Debug, // break into the debugger
+    Screenshot,
+    Record, // start or stop recording
+}
+
+#[cfg_attr(not(any(feature = "sdl", feature = "minifb")), allow(dead_code))] // only frontends make these
+pub enum Event {
+    Quit,
+    Key(KeyCode, bool), // joypad key, pressed
+    Hotkey(Hotkey),
+}
+
+pub trait Frontend {
+    // Show a finished frame, pixels as gpu::rgb takes them
+    fn present(&mut self, frame: &[u16]);
+    // Everything that happened since the last poll
+    fn poll(&mut self) -> Vec<Event>;
+    // Backends without sound drop it
+    fn queue_audio(&mut self, _samples: &[i16]) {}
+}
+
+pub fn open(name: Option<&str>) -> Result<Box<dyn Frontend>, String> {
+    let name = match name.or(BACKENDS.first().cloned()) {
+        Some(n) => n,
+        None => return Err("built without a frontend, enable the sdl or minifb feature".to_string())
+    };
+    return match name {
+        #[cfg(feature = "sdl")]
+        "sdl" => Ok(Box::new(sdl::Sdl::new()?)),
+        #[cfg(feature = "minifb")]
+        "minifb" => Ok(Box::new(minifb::MiniFb::new()?)),
+        _ => Err(format!("unknown frontend {}, built with: {}", name, BACKENDS.join(" ")))
+    };
+}
@@ -0,0 +1,99 @@
+use crate::consts::*;
+use crate::gpu::rgb;
+use crate::input::KeyCode;
+use super::{Event, Frontend, Hotkey, SAMPLE_RATE};
+use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
+    event::Event as SdlEvent,
+    EventPump,
+    keyboard::Keycode,
+    rect::Rect,
+    render::Canvas,
+};
+
+pub struct Sdl {
+    canvas: Canvas<sdl2::video::Window>,
+    event_pump: EventPump,
+    audio: Option<AudioQueue<i16>>, // None if there's no sound device
+}
+
+impl Sdl {
+    pub fn new() -> Result<Sdl, String> {
+        let ctx = sdl2::init()?;
+        let canvas = ctx.video()?
+            .window("Gameboy Emu", (WIDTH*SCALE) as u32, (HEIGHT*SCALE) as u32)
+            .position_centered()
+            .build().map_err(|e| e.to_string())?
+            .into_canvas()
+            .build().map_err(|e| e.to_string())?;
+        let spec = AudioSpecDesired { freq: Some(SAMPLE_RATE as i32), channels: Some(2), samples: None };
+        let audio = ctx.audio().and_then(|a| a.open_queue(None, &spec)).ok();
+        if let Some(a) = &audio {
+            a.resume();
+        }
+        let mut front = Sdl {
+            canvas,
+            event_pump: ctx.event_pump()?,
+            audio,
+        };
+
+        front.canvas.set_draw_color(PAL_0);
+        front.canvas.clear();
+        front.canvas.present();
+
+        return Ok(front);
+    }
+
+    fn input_key(key: Keycode) -> KeyCode {
+        match key {
+            Keycode::Return => KeyCode::Start,
+            Keycode::Space => KeyCode::Select,
+            Keycode::E => KeyCode::B,
+            Keycode::Q => KeyCode::A,
+            Keycode::S => KeyCode::Down,
+            Keycode::W => KeyCode::Up,
+            Keycode::A => KeyCode::Left,
+            Keycode::D => KeyCode::Right,
+            _ => KeyCode::Uk
+        }
+    }
+}
+
+impl Frontend for Sdl {
+    fn present(&mut self, frame: &[u16]) {
+        for (i, pix) in frame.iter().enumerate() {
+            let (x, y) = ((i % WIDTH) as i32, (i / WIDTH) as i32);
+            self.canvas.set_draw_color(rgb(*pix));
+
+            let r = Rect::new(x*SCALE as i32, y*SCALE as i32, SCALE as u32, SCALE as u32);
+
+            self.canvas.fill_rect(r).unwrap_or_default();
+        }
+        self.canvas.present();
+    }
+
+    fn poll(&mut self) -> Vec<Event> {
+        let mut evs = Vec::new();
+        for event in self.event_pump.poll_iter() {
+            match event {
+                SdlEvent::Quit {..} | SdlEvent::KeyDown { keycode: Some(Keycode::Escape), .. } => evs.push(Event::Quit),
+                SdlEvent::KeyDown { keycode: Some(Keycode::F12), .. } => evs.push(Event::Hotkey(Hotkey::Debug)),
+                SdlEvent::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => evs.push(Event::Hotkey(Hotkey::Screenshot)),
+                SdlEvent::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => evs.push(Event::Hotkey(Hotkey::Record)),
+                SdlEvent::KeyDown { keycode: Some(k), repeat: false, .. } => evs.push(Event::Key(Sdl::input_key(k), true)),
+                SdlEvent::KeyUp { keycode: Some(k), .. } => evs.push(Event::Key(Sdl::input_key(k), false)),
+                _ => {}
+            }
+        }
+        return evs;
+    }
+
+    fn queue_audio(&mut self, samples: &[i16]) {
+        if let Some(a) = &self.audio {
+            // Don't let it fall behind when we run faster than real time
+            if a.size() < SAMPLE_RATE {
+                a.queue(samples);
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{cpu, gpu, input, mem, profiler, rewind, symbols, timer};
+use std::collections::HashSet;
+
+// The whole machine
+#[derive(Default)]
+pub struct Gb {
+    pub cpu: cpu::Cpu,
+    pub gpu: gpu::Gpu,
+    pub mem: mem::Mem,
+    pub input: input::Input,
+    pub timer: timer::Timer,
+    pub hist: rewind::History,
+    pub prof: Option<profiler::Profiler>,
+    pub syms: symbols::Symbols,
+    warned: HashSet<(u16, u16)>, // bank, addr of RETs we already warned about
+}
+
+impl Gb {
+    // Run one instruction and catch the rest of the hardware up to it
+    pub fn exec(&mut self) {
+        // The cpu sits out HDMA/GDMA copies, everything else keeps going
+        let stall = std::mem::take(&mut self.mem.dma_stall);
+        let double = self.mem.double_speed(); // what this instruction runs at, STOP may switch it
+        if stall > 0 {
+            self.cpu.clk += stall;
+        } else {
+            let cdl = self.mem.cdl.clone();
+            if let Some(c) = &cdl {
+                c.borrow_mut().begin(&self.cpu, &self.mem);
+            }
+            // clk runs at 4 MHz like the gpu, in double speed instructions take half
+            let start = self.cpu.clk;
+            cpu::cpu_cycle(&mut self.cpu, &mut self.mem);
+            if double {
+                self.cpu.clk = start + (self.cpu.clk - start) / 2;
+            }
+            // A speed switch stops the cpu for 2050 M-cycles at the speed it was running
+            if self.mem.double_speed() != double {
+                self.cpu.clk += (2050 * 4) >> double as u32;
+            }
+            if let Some(c) = &cdl {
+                c.borrow_mut().end(&self.cpu, &self.mem);
+            }
+        }
+        gpu::gpu_cycle(&mut self.gpu, &mut self.mem, self.cpu.clk);
+        // HBlank DMA waits while the cpu is halted, the block goes when it wakes
+        if self.mem.hblank && self.cpu.halt == 0 {
+            self.mem.hblank = false;
+            self.mem.hdma_hblank();
+        }
+        if self.mem.input_update {
+            self.input.update(&mut self.mem);
+        }
+        self.timer.inc(self.cpu.clk, double, &mut self.mem);
+    }
+
+    // exec, keeping rewind history and the profile
+    pub fn step(&mut self) {
+        rewind::record(self);
+        if let Some(p) = &mut self.prof {
+            p.begin(&self.cpu, &self.mem);
+        }
+        self.exec();
+        if let Some(p) = &mut self.prof {
+            p.end(&self.cpu);
+        }
+        if let Some((site, to, f)) = self.cpu.bad_ret.take() {
+            if self.warned.insert((self.mem.bank_of(site), site)) {
+                eprintln!("warning: RET at {} went to {}, the call at {} expects {} (SP moved?)",
+                    self.loc(site), self.loc(to), self.loc(f.site), self.loc(f.ret));
+            }
+        }
+        self.hist.steps += 1;
+    }
+
+    // bank:addr, with the closest label if there is one
+    pub fn loc(&self, addr: u16) -> String {
+        let bank = self.mem.bank_of(addr);
+        return match self.syms.near(bank, addr) {
+            Some(l) => format!("{:02X}:{:04X} <{}>", bank, addr, l),
+            None => format!("{:02X}:{:04X}", bank, addr),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::DIVTP;
+    use std::rc::Rc;
+
+    // ld a,1 / ldh [$4D],a / stop, then nops
+    fn cgb() -> Gb {
+        let mut gb = Gb::default();
+        let rom = Rc::make_mut(&mut gb.mem.rom);
+        rom[0x143] = 0x80;
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        gb.mem.power_on(mem::Model::Cgb);
+        gb.cpu.pc = 0x100;
+        return gb;
+    }
+
+    // Clocks STOP took, then check DIV starts over from the switch
+    fn switch(gb: &mut Gb, nop_clks: u64) -> u64 {
+        gb.cpu.pc = 0x100;
+        gb.exec();
+        gb.exec();
+        let start = gb.cpu.clk;
+        gb.exec();
+        let clks = gb.cpu.clk - start;
+        assert_eq!(gb.mem.read(DIVTP), 0);
+        gb.cpu.pc = 0x110;
+        for _ in 0..63 {
+            gb.exec();
+        }
+        assert_eq!(gb.cpu.clk - start - clks, 63 * nop_clks);
+        assert_eq!(gb.mem.read(DIVTP), 0);
+        gb.exec();
+        assert_eq!(gb.mem.read(DIVTP), 1);
+        return clks;
+    }
+
+    #[test]
+    fn speed_switch() {
+        let mut gb = cgb();
+        // Some clocks towards the next DIV tick that the switch throws away
+        gb.cpu.pc = 0x110;
+        for _ in 0..30 {
+            gb.exec();
+        }
+        assert_eq!(switch(&mut gb, 2), 8 + 2050 * 4);
+        assert!(gb.mem.double_speed());
+        assert_eq!(switch(&mut gb, 4), 4 + 2050 * 2);
+        assert!(!gb.mem.double_speed());
+    }
+
+    // FF51-FF54 from src and dst, then FF55. WRAM from src counts up from 1
+    fn hdma(gb: &mut Gb, src: u16, dst: u16, val: u8) {
+        for i in 0..0x80 {
+            gb.mem.write(src + i, i as u8 + 1);
+        }
+        for (i, b) in src.to_be_bytes().iter().chain(dst.to_be_bytes().iter()).enumerate() {
+            gb.mem.write(0xFF51 + i as u16, *b);
+        }
+        gb.mem.write(0xFF55, val);
+    }
+
+    fn vram(gb: &Gb, from: u16, len: u16) -> Vec<u8> {
+        return (from..from + len).map(|a| gb.mem.vram(0, a)).collect();
+    }
+
+    // Run to the next LY, then FF55 and how much of 0x8000-0x803F has been copied
+    fn line(gb: &mut Gb) -> (u8, usize) {
+        let ly = gb.mem.read(0xFF44);
+        while gb.mem.read(0xFF44) == ly {
+            gb.exec();
+        }
+        return (gb.mem.read(0xFF55), vram(gb, 0x8000, 0x40).iter().filter(|b| **b != 0).count());
+    }
+
+    #[test]
+    fn gdma() {
+        let mut gb = cgb();
+        gb.cpu.pc = 0x110;
+        hdma(&mut gb, 0xC000, 0x8100, 0x01);
+        assert_eq!(gb.mem.read(0xFF55), 0xFF);
+        assert_eq!(vram(&gb, 0x8100, 0x30), (1..=0x20).chain([0; 16]).collect::<Vec<u8>>());
+        // 8 M-cycles a block with the cpu stopped
+        let start = gb.cpu.clk;
+        gb.exec();
+        assert_eq!((gb.cpu.clk - start, gb.cpu.pc), (64, 0x110));
+        gb.exec();
+        assert_eq!(gb.cpu.pc, 0x111);
+
+        // It ends at the end of VRAM, whatever length was left
+        hdma(&mut gb, 0xC000, 0x9FE0, 0x03);
+        assert_eq!(gb.mem.read(0xFF55), 0xFF);
+        assert_eq!(gb.mem.dma_stall, 64);
+        assert_eq!(vram(&gb, 0x9FE0, 0x20), (1..=0x20).collect::<Vec<u8>>());
+        assert_eq!(vram(&gb, 0x8000, 0x10), [0; 16]);
+    }
+
+    #[test]
+    fn hblank_dma() {
+        let mut gb = cgb();
+        gb.cpu.pc = 0x110;
+        hdma(&mut gb, 0xC000, 0x8000, 0x82);
+        assert_eq!(gb.mem.read(0xFF55), 0x02);
+        assert_eq!(vram(&gb, 0x8000, 0x10), [0; 16]);
+        // A block each HBlank, FF55 counts down to 0xFF when it's done
+        let lines: Vec<(u8, usize)> = (0..4).map(|_| line(&mut gb)).collect();
+        assert_eq!(lines, [(0x01, 16), (0x00, 32), (0xFF, 48), (0xFF, 48)]);
+        assert_eq!(vram(&gb, 0x8000, 0x30), (1..=0x30).collect::<Vec<u8>>());
+
+        // Running past the end of VRAM ends it early
+        hdma(&mut gb, 0xC000, 0x9FF0, 0x82);
+        assert_eq!(line(&mut gb).0, 0xFF);
+        assert_eq!(vram(&gb, 0x9FF0, 0x10), (1..=0x10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn hblank_dma_cancel() {
+        let mut gb = cgb();
+        gb.cpu.pc = 0x110;
+        hdma(&mut gb, 0xC000, 0x8000, 0x83);
+        assert_eq!(line(&mut gb), (0x02, 16));
+        // Bit 7 clear stops it, FF55 keeps the length left with bit 7 set
+        gb.mem.write(0xFF55, 0x00);
+        assert_eq!(gb.mem.read(0xFF55), 0x82);
+        assert_eq!(line(&mut gb), (0x82, 16));
+        assert_eq!(line(&mut gb), (0x82, 16));
+    }
+
+    #[test]
+    fn hblank_dma_halt() {
+        let mut gb = cgb();
+        Rc::make_mut(&mut gb.mem.rom)[0x110] = 0x76; // halt
+        gb.cpu.pc = 0x110;
+        gb.mem.write(0xFFFF, 0x01);
+        gb.mem.write(0xFF0F, 0x00);
+        hdma(&mut gb, 0xC000, 0x8000, 0x82);
+        // Nothing is copied while halted, VBlank wakes it
+        for _ in 0..144 {
+            assert_eq!(line(&mut gb), (0x02, 0));
+        }
+        gb.exec();
+        assert_eq!(gb.cpu.halt, 0);
+        assert_eq!((gb.mem.read(0xFF55), vram(&gb, 0x8000, 0x10)), (0x01, (1..=0x10).collect::<Vec<u8>>()));
+        // The rest go on the next frame's lines
+        while gb.mem.read(0xFF44) != 2 {
+            line(&mut gb);
+        }
+        assert_eq!(gb.mem.read(0xFF55), 0xFF);
+        assert_eq!(vram(&gb, 0x8000, 0x30), (1..=0x30).collect::<Vec<u8>>());
+    }
+}
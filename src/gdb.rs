@@ -0,0 +1,475 @@
+// GDB remote serial protocol stub
+// Registers are numbered a f b c d e h l sp pc, the 16 bit ones little endian
+use crate::consts::*;
+use crate::gb::Gb;
+use crate::mem::{Watch, WATCH_R, WATCH_W};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.core">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="f" bitsize="8"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+const REGS: [usize; 8] = [A, F, B, C, D, E, H, L];
+
+enum Stop {
+    Trap,
+    Break,
+    Int,
+    Watch(u16, u8),
+}
+
+pub struct Stub {
+    sock: TcpStream,
+    breaks: Vec<u16>,
+    step: bool,
+    brk: bool, // ctrl-c or initial attach
+    attached: bool,
+}
+
+fn hex(s: &str) -> Option<u32> {
+    return u32::from_str_radix(s, 16).ok();
+}
+
+// Works on bytes, the packet may hold anything
+fn unhex(s: &str) -> Vec<u8> {
+    let digit = |b: u8| (b as char).to_digit(16);
+    return s.as_bytes().chunks_exact(2)
+        .filter_map(|p| Some((digit(p[0])? << 4 | digit(p[1])?) as u8))
+        .collect();
+}
+
+impl Stub {
+    // Blocks until gdb connects
+    pub fn listen(port: u16) -> io::Result<Stub> {
+        let lst = TcpListener::bind(("127.0.0.1", port))?;
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+        let (sock, peer) = lst.accept()?;
+        println!("gdb connected from {}", peer);
+        return Stub::new(sock);
+    }
+
+    pub fn new(sock: TcpStream) -> io::Result<Stub> {
+        sock.set_nodelay(true)?;
+        return Ok(Stub {
+            sock,
+            breaks: Vec::new(),
+            step: false,
+            brk: true,
+            attached: true,
+        });
+    }
+
+    // Look for a ctrl-c without blocking, called once a frame
+    pub fn poll(&mut self) {
+        if !self.attached {
+            return;
+        }
+        let mut b = [0u8; 64];
+        self.sock.set_nonblocking(true).unwrap_or_default();
+        match self.sock.read(&mut b) {
+            Ok(0) => self.detach(),
+            Ok(n) => self.brk |= b[..n].contains(&0x03),
+            Err(_) => {}
+        }
+        self.sock.set_nonblocking(false).unwrap_or_default();
+    }
+
+    fn detach(&mut self) {
+        self.attached = false;
+        self.breaks.clear();
+    }
+
+    // Called before every instruction but the one we stopped at, hands control to gdb on a stop
+    pub fn check(&mut self, gb: &mut Gb) {
+        if !self.attached {
+            return;
+        }
+        let stop = if self.brk {
+            Some(Stop::Int)
+        } else if let Some((addr, kind)) = gb.mem.watch_hit.take() {
+            Some(Stop::Watch(addr, kind))
+        } else if self.step {
+            Some(Stop::Trap)
+        } else if self.breaks.contains(&gb.cpu.pc) {
+            Some(Stop::Break)
+        } else {
+            None
+        };
+
+        if let Some(s) = stop {
+            self.brk = false;
+            self.step = false;
+            let reply = match s {
+                Stop::Trap => "S05".to_string(),
+                Stop::Break => "T05swbreak:;".to_string(),
+                Stop::Int => "S02".to_string(),
+                Stop::Watch(addr, kind) => {
+                    let name = match kind {
+                        WATCH_W => "watch",
+                        WATCH_R => "rwatch",
+                        _ => "awatch"
+                    };
+                    format!("T05{}:{:04x};", name, addr)
+                }
+            };
+            self.serve(gb, &reply);
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let sum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+        let pkt = format!("${}#{:02x}", data, sum);
+        if self.sock.write_all(pkt.as_bytes()).is_err() {
+            self.detach();
+        }
+    }
+
+    // Next packet body, None if gdb went away. Bad checksums get a - and gdb resends
+    fn recv(&mut self) -> Option<String> {
+        loop {
+            let (data, sum) = self.recv_raw()?;
+            let want = data.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+            if unhex(&String::from_utf8_lossy(&sum)) != [want] {
+                self.sock.write_all(b"-").ok()?;
+                continue;
+            }
+            self.sock.write_all(b"+").ok()?;
+            return Some(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    // $data#xx, returns data and xx
+    fn recv_raw(&mut self) -> Option<(Vec<u8>, [u8; 2])> {
+        let mut b = [0u8; 1];
+        loop {
+            match self.sock.read(&mut b) {
+                Ok(1) if b[0] == b'$' => break,
+                Ok(1) => continue, // acks and stray ctrl-c
+                _ => return None
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match self.sock.read(&mut b) {
+                Ok(1) if b[0] == b'#' => break,
+                Ok(1) => data.push(b[0]),
+                _ => return None
+            }
+        }
+        let mut sum = [0u8; 2];
+        self.sock.read_exact(&mut sum).ok()?;
+        return Some((data, sum));
+    }
+
+    fn get_reg(&self, gb: &Gb, n: usize) -> Option<String> {
+        return match n {
+            0..=7 => Some(format!("{:02x}", gb.cpu.regs[REGS[n]])),
+            8 => Some(format!("{:02x}{:02x}", gb.cpu.sp as u8, gb.cpu.sp >> 8)),
+            9 => Some(format!("{:02x}{:02x}", gb.cpu.pc as u8, gb.cpu.pc >> 8)),
+            _ => None
+        };
+    }
+
+    fn set_reg(&self, gb: &mut Gb, n: usize, v: &[u8]) -> bool {
+        match (n, v.len()) {
+            (0..=7, 1) => gb.cpu.regs[REGS[n]] = v[0],
+            (8, 2) => gb.cpu.sp = u16::from_le_bytes([v[0], v[1]]),
+            (9, 2) => gb.cpu.pc = u16::from_le_bytes([v[0], v[1]]),
+            _ => return false
+        }
+        return true;
+    }
+
+    // Z/z packets, type,addr,kind
+    fn point(&mut self, gb: &mut Gb, add: bool, args: &str) -> &'static str {
+        let p: Vec<u32> = args.split(',').filter_map(hex).collect();
+        if p.len() < 3 {
+            return "E01";
+        }
+        let (addr, len) = (p[1] as u16, p[2].max(1) as u16);
+        let kind = match p[0] {
+            0 | 1 => {
+                if add {
+                    self.breaks.push(addr);
+                } else {
+                    self.breaks.retain(|b| *b != addr);
+                }
+                return "OK";
+            }
+            2 => WATCH_W,
+            3 => WATCH_R,
+            4 => WATCH_R | WATCH_W,
+            _ => return ""
+        };
+        if add {
+            gb.mem.watches.push(Watch { addr, len, kind });
+        } else {
+            gb.mem.watches.retain(|w| !(w.addr == addr && w.len == len && w.kind == kind));
+        }
+        return "OK";
+    }
+
+    fn read_mem(&self, gb: &Gb, args: &str) -> String {
+        let p: Vec<u32> = args.split(',').filter_map(hex).collect();
+        if p.len() != 2 {
+            return "E01".to_string();
+        }
+        return (0..p[1]).map(|i| format!("{:02x}", gb.mem.read((p[0] + i) as u16))).collect();
+    }
+
+    fn write_mem(&self, gb: &mut Gb, args: &str) -> &'static str {
+        let (hdr, dat) = match args.find(':') {
+            Some(i) => (&args[..i], &args[i + 1..]),
+            None => return "E01"
+        };
+        let addr = match hdr.split(',').next().and_then(hex) {
+            Some(a) => a,
+            None => return "E01"
+        };
+        for (i, b) in unhex(dat).iter().enumerate() {
+            gb.mem.write((addr + i as u32) as u16, *b);
+        }
+        return "OK";
+    }
+
+    fn xfer(&self, args: &str) -> String {
+        // features:read:target.xml:offset,length
+        let p: Vec<&str> = args.split(':').collect();
+        if p.len() != 4 || p[0] != "features" || p[1] != "read" || p[2] != "target.xml" {
+            return "".to_string();
+        }
+        let ol: Vec<usize> = p[3].split(',').filter_map(|s| hex(s).map(|v| v as usize)).collect();
+        if ol.len() != 2 {
+            return "E01".to_string();
+        }
+        let st = ol[0].min(TARGET_XML.len());
+        let end = (st + ol[1]).min(TARGET_XML.len());
+        let more = if end < TARGET_XML.len() { "m" } else { "l" };
+        return format!("{}{}", more, &TARGET_XML[st..end]);
+    }
+
+    // Talk to gdb until it resumes us
+    fn serve(&mut self, gb: &mut Gb, stop: &str) {
+        self.send(stop);
+        while self.attached {
+            let pkt = match self.recv() {
+                Some(p) => p,
+                None => {
+                    self.detach();
+                    return;
+                }
+            };
+            let (cmd, args) = pkt.split_at(pkt.chars().next().map_or(0, char::len_utf8));
+
+            let reply = match cmd {
+                "?" => stop.to_string(),
+                "g" => (0..10).filter_map(|n| self.get_reg(gb, n)).collect(),
+                "G" => {
+                    let v = unhex(args);
+                    if v.len() < 12 {
+                        "E01".to_string()
+                    } else {
+                        for n in 0..8 {
+                            self.set_reg(gb, n, &v[n..n + 1]);
+                        }
+                        self.set_reg(gb, 8, &v[8..10]);
+                        self.set_reg(gb, 9, &v[10..12]);
+                        "OK".to_string()
+                    }
+                }
+                "p" => hex(args).and_then(|n| self.get_reg(gb, n as usize)).unwrap_or("E01".to_string()),
+                "P" => {
+                    let mut kv = args.split('=');
+                    let ok = match (kv.next().and_then(hex), kv.next()) {
+                        (Some(n), Some(v)) => self.set_reg(gb, n as usize, &unhex(v)),
+                        _ => false
+                    };
+                    if ok { "OK" } else { "E01" }.to_string()
+                }
+                "m" => self.read_mem(gb, args),
+                "M" => self.write_mem(gb, args).to_string(),
+                "Z" => self.point(gb, true, args).to_string(),
+                "z" => self.point(gb, false, args).to_string(),
+                "c" | "s" => {
+                    if let Some(a) = hex(args) {
+                        gb.cpu.pc = a as u16;
+                    }
+                    self.step = cmd == "s";
+                    return;
+                }
+                "k" => {
+                    gb.cpu.stop = 1;
+                    return;
+                }
+                "D" => {
+                    self.send("OK");
+                    self.detach();
+                    gb.mem.watches.clear();
+                    return;
+                }
+                "H" => "OK".to_string(),
+                "q" => {
+                    if args.starts_with("Supported") {
+                        "PacketSize=1000;qXfer:features:read+;swbreak+".to_string()
+                    } else if let Some(x) = args.strip_prefix("Xfer:") {
+                        self.xfer(x)
+                    } else if args == "Attached" {
+                        "1".to_string()
+                    } else if args == "fThreadInfo" {
+                        "m1".to_string()
+                    } else if args == "sThreadInfo" {
+                        "l".to_string()
+                    } else if args == "C" {
+                        "QC1".to_string()
+                    } else {
+                        "".to_string()
+                    }
+                }
+                _ => "".to_string()
+            };
+            self.send(&reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Plays gdb's side: sends a packet, checks the ack and the reply
+    struct Client(TcpStream);
+
+    impl Client {
+        fn byte(&mut self) -> u8 {
+            let mut b = [0u8; 1];
+            self.0.read_exact(&mut b).unwrap();
+            return b[0];
+        }
+
+        fn reply(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b => data.push(b)
+                }
+            }
+            let sum = [self.byte(), self.byte()];
+            let want = data.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+            assert_eq!(unhex(std::str::from_utf8(&sum).unwrap()), [want]);
+            return String::from_utf8(data).unwrap();
+        }
+
+        fn raw(&mut self, pkt: &[u8]) -> u8 {
+            self.0.write_all(pkt).unwrap();
+            return self.byte();
+        }
+
+        fn send(&mut self, data: &str) {
+            let sum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+            assert_eq!(self.raw(format!("${}#{:02x}", data, sum).as_bytes()), b'+');
+        }
+
+        fn ask(&mut self, data: &str) -> String {
+            self.send(data);
+            return self.reply();
+        }
+    }
+
+    #[test]
+    fn session() {
+        let lst = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = lst.local_addr().unwrap();
+        let gdb = thread::spawn(move || {
+            let mut c = Client(TcpStream::connect(addr).unwrap());
+            assert_eq!(c.reply(), "S02");
+            assert_eq!(c.ask("?"), "S02");
+            assert_eq!(c.ask("g"), "0102030405060708feff0001");
+            assert_eq!(c.ask("mc000,3"), "aabbcc");
+            assert_eq!(c.ask("Z0,150,1"), "OK");
+            // A bad checksum is nacked, the resend goes through
+            assert_eq!(c.raw(b"$?#00"), b'-');
+            assert_eq!(c.ask("?"), "S02");
+            // Odd bytes must not take the stub down
+            assert_eq!(c.ask("\u{e9}"), "");
+            assert_eq!(c.ask("P0=a\u{e9}"), "E01");
+            assert_eq!(c.ask("Ga\u{e9}"), "E01");
+            c.send("s");
+            assert_eq!(c.reply(), "S05");
+            c.send("c");
+            assert_eq!(c.reply(), "T05swbreak:;");
+            c.send("D");
+            assert_eq!(c.reply(), "OK");
+        });
+
+        let mut gb = Gb::default();
+        for (i, r) in REGS.iter().enumerate() {
+            gb.cpu.regs[*r] = i as u8 + 1;
+        }
+        gb.cpu.sp = 0xFFFE;
+        gb.cpu.pc = 0x100;
+        for (i, v) in [0xAA, 0xBB, 0xCC].iter().enumerate() {
+            gb.mem.write(0xC000 + i as u16, *v);
+        }
+        let mut stub = Stub::new(lst.accept().unwrap().0).unwrap();
+
+        stub.check(&mut gb); // attach, serves until s
+        assert!(stub.step);
+        stub.check(&mut gb); // the step, serves until c
+        assert!(!stub.step);
+        stub.check(&mut gb); // 0x100 isn't a breakpoint
+        gb.cpu.pc = 0x150;
+        stub.check(&mut gb); // serves until D
+        assert!(!stub.attached);
+        gdb.join().unwrap();
+    }
+
+    // The interrupt poll reads IE before every instruction, that isn't the cpu reading it
+    #[test]
+    fn rwatch_ie() {
+        let lst = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = lst.local_addr().unwrap();
+        let gdb = thread::spawn(move || {
+            let mut c = Client(TcpStream::connect(addr).unwrap());
+            assert_eq!(c.reply(), "S02");
+            assert_eq!(c.ask("Z3,ffff,1"), "OK");
+            c.send("c");
+            assert_eq!(c.reply(), "T05rwatch:ffff;");
+            assert_eq!(c.ask("p9"), "0501");
+            c.send("D");
+            assert_eq!(c.reply(), "OK");
+        });
+
+        // nop x3 / ldh a,[$FF] / jr @
+        let mut gb = Gb::default();
+        std::rc::Rc::make_mut(&mut gb.mem.rom)[0x100..0x107].copy_from_slice(&[0x00, 0x00, 0x00, 0xF0, 0xFF, 0x18, 0xFE]);
+        gb.cpu.pc = 0x100;
+        let mut stub = Stub::new(lst.accept().unwrap().0).unwrap();
+        for _ in 0..100 {
+            stub.check(&mut gb);
+            if !stub.attached {
+                break;
+            }
+            gb.step();
+        }
+        assert!(!stub.attached);
+        gdb.join().unwrap();
+    }
+}
@@ -1,8 +1,8 @@
 use crate::consts::*;
-use crate::frontend::FrontEnd;
-use crate::mem::Mem;
+use crate::mem::{Mem, Model};
+use std::collections::VecDeque;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 enum GpuMode {
     OAM,
     VRAM,
@@ -10,12 +10,135 @@ enum GpuMode {
     VBLANK
 }
 
+const LINE_DOTS: u16 = 456;
+const OAM_DOTS: u16 = 80;
+
+// Pixel of a frame as RGB. DMG pixels are shades 0-3, CGB ones are
+// 0x8000 | 15 bit color, red in the low bits
+pub fn rgb(pix: u16) -> (u8, u8, u8) {
+    if pix & 0x8000 == 0 {
+        return SHADES[pix as usize & 3];
+    }
+    let c = |sh: u16| { let v = (pix >> sh) as u8 & 0x1F; v << 3 | v >> 2 };
+    return (c(0), c(5), c(10));
+}
+
+// Color cn of CGB palette p from palette ram
+fn cgb_color(pal: &[u8; 64], p: u8, cn: u8) -> u16 {
+    let i = p as usize*8 + cn as usize*2;
+    return 0x8000 | (pal[i] as u16 | (pal[i + 1] as u16) << 8) & 0x7FFF;
+}
+
+// A sprite on the current line, from the OAM scan
+#[derive(Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attr: u8,
+    idx: u8, // OAM index
+}
+
+// A background or window pixel waiting to go out
+#[derive(Clone, Copy)]
+struct BgPix {
+    color: u8,
+    pal: u8, // CGB palette, 0 on DMG
+    prio: bool, // CGB attribute bit 7, colors 1-3 go over sprites
+}
+
+// A sprite pixel waiting to be mixed with the background
+#[derive(Clone, Copy)]
+struct ObjPix {
+    color: u8, // 0 is transparent
+    pal: u8, // OBP0 or OBP1, or CGB palette 0-7
+    behind: bool, // background colors 1-3 win
+    idx: u8, // OAM index, CGB priority
+}
+
+// Mode 3 state. The fetcher reads a tile row every 8 dots into the background
+// FIFO while one pixel a dot is shifted out, mixed with the sprite FIFO
+#[derive(Clone)]
+struct Fifo {
+    bg: VecDeque<BgPix>,
+    obj: VecDeque<ObjPix>, // lined up with bg
+    step: u8, // dot of the current tile fetch
+    tile_x: u8, // tile column being fetched
+    tile: u8,
+    attr: u8, // CGB map attributes of the tile
+    lo: u8,
+    hi: u8,
+    first: bool, // the first fetch of the line is thrown away
+    window: bool, // fetching the window instead of the background
+    discard: u8, // pixels left to drop for SCX fine scroll
+    lx: u8, // pixels shifted out
+    sprites: Vec<Sprite>, // on this line, by X
+    next_sprite: usize, // first sprite not fetched yet
+    stall: u8, // dots left of a sprite fetch, nothing moves
+    penalized: Option<(bool, u8)>, // last tile that made a sprite wait for the fetcher
+}
+
+impl Default for Fifo {
+    fn default() -> Fifo {
+        Fifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: 0,
+            tile_x: 0,
+            tile: 0,
+            attr: 0,
+            lo: 0,
+            hi: 0,
+            first: true,
+            window: false,
+            discard: 0,
+            lx: 0,
+            sprites: Vec::with_capacity(40),
+            next_sprite: 0,
+            stall: 0,
+            penalized: None,
+        }
+    }
+}
+
 pub struct Gpu {
     mode: GpuMode,
-    clk: u64,
+    dot: u16, // dot of the current line
+    ly: u8, // line being drawn, LY reads 0 for most of 153
+    stat_line: bool, // the STAT interrupt sources ORed, IF is set when it goes high
+    lcd: bool, // LCDC bit 7 as of the last cycle
+    lcd_start: bool, // first line after the LCD came on, no OAM scan
+    skip_frame: bool, // first frame after the LCD came on, not shown
     prev: u64,
+    fifo: Fifo,
     pub frames: f64,
-    pub front: FrontEnd,
+    screen: [u16; WIDTH*HEIGHT], // pixels of the frame being drawn, see rgb
+    pub frame: [u16; WIDTH*HEIGHT], // last finished frame
+    pub frame_ready: bool, // frame was finished since the frontend last took it
+    pub frame_no: u64, // frames finished since power on
+    wy_hit: bool, // LY matched WY this frame, the window can show
+    wline: u8, // window's own line counter, counts lines it was drawn on
+    wx166: bool, // window started at WX 166, it covers the next line
+}
+
+// Everything but the finished frame, for rewinding
+#[derive(Clone)]
+pub struct GpuState {
+    mode: GpuMode,
+    dot: u16,
+    ly: u8,
+    stat_line: bool,
+    lcd: bool,
+    lcd_start: bool,
+    skip_frame: bool,
+    prev: u64,
+    fifo: Fifo,
+    frames: f64,
+    screen: [u16; WIDTH*HEIGHT],
+    frame_no: u64,
+    wy_hit: bool,
+    wline: u8,
+    wx166: bool,
 }
 
 
@@ -23,215 +146,988 @@ impl Default for Gpu {
     fn default() -> Gpu {
         let gp = Gpu {
             mode: GpuMode::OAM,
-            clk: 0,
+            dot: 0,
+            ly: 0,
+            stat_line: false,
+            lcd: true,
+            lcd_start: false,
+            skip_frame: false,
             frames: 0.,
             prev: 0,
-            front: FrontEnd::default()
+            fifo: Fifo::default(),
+            screen: [0; WIDTH*HEIGHT],
+            frame: [0; WIDTH*HEIGHT],
+            frame_ready: false,
+            frame_no: 0,
+            wy_hit: false,
+            wline: 0,
+            wx166: false,
         };
         return gp;
     }
 }
 
 impl Gpu {
-    fn lcd_on(&self, gb_mem: &Mem) -> bool {
-        return gb_mem.read(LCD_CTLP) & 0x80 != 0;
+    pub fn save(&self) -> GpuState {
+        return GpuState {
+            mode: self.mode,
+            dot: self.dot,
+            ly: self.ly,
+            stat_line: self.stat_line,
+            lcd: self.lcd,
+            lcd_start: self.lcd_start,
+            skip_frame: self.skip_frame,
+            prev: self.prev,
+            fifo: self.fifo.clone(),
+            frames: self.frames,
+            screen: self.screen,
+            frame_no: self.frame_no,
+            wy_hit: self.wy_hit,
+            wline: self.wline,
+            wx166: self.wx166,
+        };
     }
 
-    fn line(&self, gb_mem: &Mem) -> u8 {
-        return gb_mem.read(SCLINEP);
+    pub fn load(&mut self, st: &GpuState) {
+        self.mode = st.mode;
+        self.dot = st.dot;
+        self.ly = st.ly;
+        self.stat_line = st.stat_line;
+        self.lcd = st.lcd;
+        self.lcd_start = st.lcd_start;
+        self.skip_frame = st.skip_frame;
+        self.prev = st.prev;
+        self.fifo = st.fifo.clone();
+        self.frames = st.frames;
+        self.screen = st.screen;
+        self.frame_no = st.frame_no;
+        self.wy_hit = st.wy_hit;
+        self.wline = st.wline;
+        self.wx166 = st.wx166;
     }
 
-    fn set_line(&mut self, gb_mem: &mut Mem, val: u8) {
-        gb_mem.write(SCLINEP, val);
-        let gint = gb_mem.read(GPU_INTS);
-        if val == self.lyc(gb_mem) {
-            if gint & 0x40 != 0  {
-                gb_mem.write(PINT_F, gb_mem.read(PINT_F) | 0x2)
-            }
-            gb_mem.write(GPU_INTS, gint | 0x4);
-        } else {
-            gb_mem.write(GPU_INTS, gint & 0xFB)
+    // The finished frame, once per VBlank
+    pub fn take_frame(&mut self) -> Option<&[u16; WIDTH*HEIGHT]> {
+        if !self.frame_ready {
+            return None;
         }
+        self.frame_ready = false;
+        return Some(&self.frame);
+    }
+
+    fn line(&self) -> u8 {
+        return self.ly;
+    }
+
+    fn set_line(&mut self, gb_mem: &mut Mem, val: u8) {
+        self.ly = val;
+        gb_mem.io[0x44] = val;
     }
 
     fn lyc(&self, gb_mem: &Mem) -> u8 {
         return gb_mem.read(LYCP);
     }
 
-    fn set_mode(&mut self, gb_mem: &mut Mem, mode: GpuMode) {
+    fn set_mode(&mut self, mode: GpuMode) {
         self.mode = mode;
+    }
+
+    // LY and the mode go to 0 and the screen goes white
+    fn lcd_off(&mut self, gb_mem: &mut Mem) {
+        self.set_line(gb_mem, 0);
+        self.set_mode(GpuMode::HBLANK);
+        self.dot = 0;
+        self.stat_line = false;
+        self.wy_hit = false;
+        self.wline = 0;
+        self.wx166 = false;
+        gb_mem.io[0x41] &= 0xFC;
+        self.screen = [if gb_mem.cgb { 0xFFFF } else { 0 }; WIDTH*HEIGHT];
+        self.frame = self.screen;
+        self.frame_ready = true;
+    }
+
+    // The first line is 4 dots short and skips the OAM scan, it reads as
+    // mode 0 until mode 3. The frame drawn after that isn't shown
+    fn lcd_on(&mut self, gb_mem: &mut Mem) {
+        self.set_line(gb_mem, 0);
+        self.set_mode(GpuMode::OAM);
+        self.dot = 4;
+        self.lcd_start = true;
+        self.skip_frame = true;
+        self.fifo.sprites.clear();
+        self.wy_hit = gb_mem.read(WYP) == 0;
+    }
+
+    // Mode and LY=LYC bits of STAT, and the interrupt line all the enabled
+    // sources share. Another source going high while it's already high
+    // doesn't fire again
+    fn update_stat(&mut self, gb_mem: &mut Mem) {
         let i_mode = match self.mode {
+            GpuMode::OAM if self.lcd_start => 0,
             GpuMode::HBLANK => 0,
             GpuMode::VBLANK => 1,
             GpuMode::OAM => 2,
             GpuMode::VRAM => 3,
         };
-        let gint = gb_mem.read(GPU_INTS) & 0xFC | i_mode;
-        gb_mem.write(GPU_INTS, gint);
-        if i_mode != 3 && gint & (1 << 3+i_mode) != 0 {
-            gb_mem.write(PINT_F, gb_mem.read(PINT_F) | 0x2)
+        let coinc = gb_mem.read(SCLINEP) == self.lyc(gb_mem);
+        let stat = gb_mem.read(GPU_INTS) & 0x78 | (coinc as u8) << 2 | i_mode;
+        gb_mem.io[0x41] = stat | 0x80;
+
+        // DMG bug: a STAT write enables every source for a moment, so it
+        // fires in HBlank, VBlank or on LY=LYC whatever was enabled
+        if gb_mem.stat_write {
+            gb_mem.stat_write = false;
+            if gb_mem.model == Model::Dmg && !self.stat_line && (i_mode < 2 || coinc) {
+                gb_mem.write(PINT_F, gb_mem.read(PINT_F) | 0x2);
+            }
         }
+
+        // Mode 2 is also checked as line 144 starts, before VBlank takes over
+        let oam = i_mode == 2 || (self.ly == 144 && self.dot == 0);
+        let line = (stat & 0x40 != 0 && coinc)
+            || (stat & 0x08 != 0 && self.mode == GpuMode::HBLANK)
+            || (stat & 0x10 != 0 && i_mode == 1)
+            || (stat & 0x20 != 0 && oam);
+        if line && !self.stat_line {
+            gb_mem.write(PINT_F, gb_mem.read(PINT_F) | 0x2);
+        }
+        self.stat_line = line;
     }
 
-    fn get_color(&self, gb_mem: &Mem, cn: u8, addr: u16) -> (u8, u8, u8) {
-        let pallete = gb_mem.read(addr);
-        let col = if cn <= 3 {
-            ((((1 << (1 + (2*cn))) & pallete != 0) as u8) << 1) | ((1 << (2*cn)) & pallete != 0) as u8
-        } else {
-            (((1 & pallete != 0) as u8) << 1) | (1 & pallete != 0) as u8
-        };
+    // Shade of color number cn through the palette at addr
+    fn get_color(&self, gb_mem: &Mem, cn: u8, addr: u16) -> u16 {
+        return (gb_mem.read(addr) as u16 >> (2*cn)) & 3;
+    }
 
-        return match col {
-            0 => PAL_0,
-            1 => PAL_1,
-            2 => PAL_2,
-            3 => PAL_3,
-            _ => (0, 0, 0),
-        };
+    // LCDC bit 0 also hides the window on DMG, on CGB it only drops priority
+    fn window_on(&self, gb_mem: &Mem) -> bool {
+        let lcdc = gb_mem.read(LCD_CTLP);
+        return lcdc & 0x20 != 0 && (gb_mem.cgb || lcdc & 0x1 != 0);
     }
 
-    fn draw_line(&mut self, gb_mem: &mut Mem) {
-        let val = gb_mem.read(LCD_CTLP);
-        let sline = self.line(gb_mem);
-        let mut bgpix = [false; 160];
+    // The first 10 sprites in OAM order that cover this line. Hidden ones
+    // (X 0 or >= 168) still count towards the 10
+    fn oam_scan(&mut self, gb_mem: &Mem) {
+        let ly = self.line() as u16;
+        let h = if gb_mem.read(LCD_CTLP) & 0x4 != 0 { 16 } else { 8 };
+        let f = &mut self.fifo;
+        f.sprites.clear();
+        for i in 0..40 {
+            let addr = SPRITE_BASE + i*4;
+            let y = gb_mem.read(addr) as u16;
+            if ly + 16 >= y && ly + 16 < y + h {
+                f.sprites.push(Sprite {
+                    y: y as u8,
+                    x: gb_mem.read(addr + 1),
+                    tile: gb_mem.read(addr + 2),
+                    attr: gb_mem.read(addr + 3),
+                    idx: i as u8,
+                });
+                if f.sprites.len() == 10 {
+                    break;
+                }
+            }
+        }
+        f.sprites.sort_by_key(|s| s.x); // stable, so OAM order breaks ties
+    }
 
-        if val & 0x1 != 0 {
-            let wy = gb_mem.read(WYP);
-            let sw = (val & 0x20 != 0) && wy <= sline;
-            let y = if sw {
-                sline - wy
-            } else {
-                (gb_mem.read(SCYP) as u16 + sline as u16) as u8
-            };
+    fn start_mode3(&mut self, gb_mem: &Mem) {
+        let win = self.wx166 && self.window_on(gb_mem);
+        let f = &mut self.fifo;
+        f.bg.clear();
+        f.obj.clear();
+        f.step = 0;
+        f.tile_x = 0;
+        f.first = true;
+        f.window = false;
+        f.discard = gb_mem.read(SCXP) & 7;
+        f.lx = 0;
+        f.next_sprite = 0;
+        f.stall = 0;
+        f.penalized = None;
+        f.window = win;
+        self.wx166 = false;
+    }
 
-            for i in 0..160 {
-                let wx = gb_mem.read(WXP);
-                let x = if sw && i >= wx {
-                    i - wx
-                } else {
-                    i.wrapping_add(gb_mem.read(SCXP))
-                };
+    // Row of the tile under the fetcher, low and high bitplanes at addr and addr+1
+    fn tile_row(&self, lcdc: u8, row: u16) -> u16 {
+        let tn = self.fifo.tile;
+        let row = if self.fifo.attr & 0x40 != 0 { 7 - row } else { row };
+        let base = if lcdc & 0x10 != 0 {
+            0x8000 + tn as u16 * 16
+        } else {
+            (0x9000 + (tn as i8 as i32) * 16) as u16
+        };
+        return base + row * 2;
+    }
 
-                let tx = (x as u16)/8;
-                let ty = ((y as u16)/8) * 32;
-                let msk = if sw { 0x40 } else { 0x8 };
-                let bgmap = if val & msk != 0 { 0x9C00 } else { 0x9800 };
-                let tnp = bgmap + tx + ty;
-                let tn = gb_mem.read(tnp);
+    // One dot of the background/window fetcher
+    fn fetch(&mut self, gb_mem: &Mem) {
+        let lcdc = gb_mem.read(LCD_CTLP);
+        let ly = self.line();
+        let (map_bit, x, y) = if self.fifo.window {
+            (0x40, self.fifo.tile_x, self.wline)
+        } else {
+            (0x08, (gb_mem.read(SCXP) / 8).wrapping_add(self.fifo.tile_x) & 31, ly.wrapping_add(gb_mem.read(SCYP)))
+        };
 
-                let bgtile = if val & 0x10 != 0 { 0x8000 } else {0x8800};
-                let tp = if bgtile == 0x8000 {
-                    bgtile + (tn as u16)*16
-                } else {
-                    bgtile + ((tn as i8 as u16 + 128)*16) as u16
-                };
+        match self.fifo.step {
+            1 => {
+                let map = if lcdc & map_bit != 0 { 0x9C00 } else { 0x9800 };
+                let addr = map + (y as u16 / 8) * 32 + x as u16;
+                self.fifo.tile = gb_mem.vram(0, addr);
+                self.fifo.attr = if gb_mem.cgb { gb_mem.vram(1, addr) } else { 0 };
+            }
+            3 => self.fifo.lo = gb_mem.vram(self.fifo.attr >> 3, self.tile_row(lcdc, y as u16 % 8)),
+            5 => self.fifo.hi = gb_mem.vram(self.fifo.attr >> 3, self.tile_row(lcdc, y as u16 % 8) + 1),
+            _ => {}
+        }
 
-                let l = (y as u16 % 8) * 2;
+        let f = &mut self.fifo;
+        if f.step < 5 || !f.bg.is_empty() {
+            f.step += 1;
+            return;
+        }
+        f.step = 0;
+        if f.first {
+            f.first = false;
+            return;
+        }
+        for i in 0..8 {
+            let b = if f.attr & 0x20 != 0 { i } else { 7 - i };
+            f.bg.push_back(BgPix {
+                color: ((f.hi >> b) & 1) << 1 | (f.lo >> b) & 1,
+                pal: f.attr & 7,
+                prio: f.attr & 0x80 != 0,
+            });
+        }
+        f.tile_x = f.tile_x.wrapping_add(1);
+    }
 
-                let l_tile = gb_mem.read(tp + l);
-                let h_tile = gb_mem.read(tp + l + 1);
+    // Mix the sprite's row into the sprite FIFO. Pixels already there win,
+    // which gives the lower X, then lower OAM index, priority. In CGB mode
+    // only the OAM index counts, unless OPRI says otherwise
+    fn fetch_sprite(&mut self, gb_mem: &Mem, s: Sprite) {
+        let lcdc = gb_mem.read(LCD_CTLP);
+        let ly = self.line();
+        // 8x16 sprites are an even tile on top of the next one, flipped as a whole
+        let h = if lcdc & 0x4 != 0 { 16 } else { 8 };
+        let mut row = ly.wrapping_add(16).wrapping_sub(s.y) as u16 & (h - 1);
+        if s.attr & 0x40 != 0 {
+            row = h - 1 - row;
+        }
+        let tile = if h == 16 { s.tile & 0xFE } else { s.tile } as u16;
+        let addr = 0x8000 + tile*16 + row*2;
+        let cgb = gb_mem.cgb;
+        let bank = if cgb { s.attr >> 3 } else { 0 };
+        let (lo, hi) = (gb_mem.vram(bank, addr), gb_mem.vram(bank, addr + 1));
 
-                let cb = (((x as i16 % 8) - 7)*(-1)) as u8;
+        let f = &mut self.fifo;
+        let pal = if cgb { s.attr & 7 } else { (s.attr >> 4) & 1 };
+        let oam_prio = cgb && gb_mem.io[0x6C] & 1 == 0;
+        for i in 0..8u8 {
+            // Screen x of this pixel is s.x - 8 + i, skip what's left of lx
+            let sx = s.x as i32 - 8 + i as i32;
+            if sx < f.lx as i32 {
+                continue;
+            }
+            let b = if s.attr & 0x20 != 0 { i } else { 7 - i };
+            let pix = ObjPix {
+                color: ((hi >> b) & 1) << 1 | (lo >> b) & 1,
+                pal,
+                behind: s.attr & 0x80 != 0,
+                idx: s.idx,
+            };
+            let k = (sx - f.lx as i32) as usize;
+            if k >= f.obj.len() {
+                f.obj.push_back(pix);
+            } else if f.obj[k].color == 0 || (oam_prio && pix.color != 0 && pix.idx < f.obj[k].idx) {
+                f.obj[k] = pix;
+            }
+        }
+    }
 
-                let cn = ((((1 << cb as u8) & h_tile != 0) as u8) << 1) | ((1 << cb as u8) & l_tile != 0) as u8;
+    // Dots mode 3 grows by for fetching s. 6 for the fetch itself, and before
+    // that up to 5 waiting for the tile under its left edge, once per tile
+    fn sprite_penalty(&mut self, gb_mem: &Mem, s: Sprite) -> u8 {
+        if s.x == 0 {
+            return 11;
+        }
+        let f = &mut self.fifo;
+        let off = if f.window {
+            s.x.wrapping_sub(gb_mem.read(WXP).wrapping_add(1))
+        } else {
+            s.x.wrapping_add(gb_mem.read(SCXP))
+        };
+        let tile = Some((f.window, off / 8));
+        let mut p = 6;
+        if f.penalized != tile {
+            f.penalized = tile;
+            p += 5 - (off % 8).min(5);
+        }
+        return p;
+    }
 
-                let col = self.get_color(gb_mem, cn, BG_PALLP);
-                bgpix[i as usize] = col.0 == 224;
-                self.front.draw_pix(i as i32, sline as i32, col);
+    // One dot of mode 3, true once the line is done
+    fn mode3_dot(&mut self, gb_mem: &Mem) -> bool {
+        if self.fifo.lx as usize >= WIDTH {
+            return true;
+        }
+        let lcdc = gb_mem.read(LCD_CTLP);
 
-            }
+        // Window starts when its left edge is reached. WX 0-6 put that edge off
+        // screen, and WX 0 matches before the SCX fine scroll is dropped, so
+        // the window loses those pixels too and jitters with SCX
+        let wx = gb_mem.read(WXP);
+        let win = self.window_on(gb_mem);
+        let f = &mut self.fifo;
+        if !f.window && win && self.wy_hit && (f.discard == 0 || wx == 0) && f.lx as u16 + 7 >= wx as u16 {
+            f.window = true;
+            f.bg.clear();
+            f.tile_x = 0;
+            f.step = 0;
+            f.discard += 7u8.saturating_sub(wx);
+            self.wx166 = wx == 166;
         }
 
-        if val & 0x2 != 0 {
-            let s2x = val & 0x4 != 0;
-            for i in 0..40 {
-                let addr = SPRITE_BASE + (i as u16)*4;
-                let y = gb_mem.read(addr) as i32 - 16;
-                let x = gb_mem.read(addr + 1) as i32 - 8;
-                let tp = gb_mem.read(addr + 2) as u16 & (0xFF - s2x as u16);
-                let attr = gb_mem.read(addr + 3);
-                let bg_prio = attr & 0x80 != 0;
-                let fy = attr & 0x40 != 0;
-                let fx = attr & 0x20 != 0;
-                let sy = (s2x as u8 + 1) * 8;
-
-                if !(y <= sline as i32 && (y+8) > sline as i32) {
-                    continue
+        // Sprites at this x are fetched before the pixel goes out
+        if lcdc & 0x2 != 0 && self.fifo.discard == 0 {
+            while let Some(s) = self.fifo.sprites.get(self.fifo.next_sprite).cloned() {
+                if s.x as u16 > self.fifo.lx as u16 + 8 {
+                    break;
                 }
+                self.fifo.next_sprite += 1;
+                self.fetch_sprite(gb_mem, s);
+                self.fifo.stall += self.sprite_penalty(gb_mem, s);
+            }
+        }
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
 
-                let line = if fy {
-                    (sy as i32 - (sline as i32 - y) - 1) as u16
-                } else {
-                    (sline as i32 - y) as u16
-                };
-
-                let sp = (0x8000 + tp as u32 * 16 + line as u32 *2) as u16;
-                let l_sprite = gb_mem.read(sp);
-                let h_sprite = gb_mem.read(sp + 1);
-                for j in (0..8).rev() {
-                    let mut cb = j as i8;
-                    if fx {
-                        cb = 7 - cb
-                    }
-
-                    let cn = ((((1 << cb as u8) & h_sprite != 0) as u8) << 1) | ((1 << cb as u8) & l_sprite != 0) as u8;
-                    let cp = (attr & 0x10 != 0) as u16 + OBJPALBP;
-                    let col = self.get_color(gb_mem, cn, cp);
-
-                    if bg_prio && (val & 0x1) != 0 && !bgpix[((x + j) % 160) as usize] {
-                        continue;
-                    }
-
-                    let pix = (x as u8).wrapping_add(7-j as u8);
-                    self.front.draw_pix(pix as i32, sline as i32, col);
-                }
+        // A pushed tile goes out from the next dot
+        let popped = self.fifo.bg.pop_front();
+        self.fetch(gb_mem);
+        let c = match popped {
+            Some(c) => c,
+            None => return false
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+
+        // LCDC bit 0 blanks the background to white on DMG, whatever BGP says.
+        // On CGB it's the master priority instead, clear puts sprites over everything
+        let cgb = gb_mem.cgb;
+        let bgc = if cgb || lcdc & 0x1 != 0 { c.color } else { 0 };
+        let mut pix = if cgb {
+            cgb_color(&gb_mem.bg_pal, c.pal, bgc)
+        } else if lcdc & 0x1 != 0 {
+            self.get_color(gb_mem, bgc, BG_PALLP)
+        } else {
+            0
+        };
+        if let Some(o) = self.fifo.obj.pop_front() {
+            let bg_wins = bgc != 0 && (o.behind || (cgb && c.prio)) && (!cgb || lcdc & 0x1 != 0);
+            if o.color != 0 && lcdc & 0x2 != 0 && !bg_wins {
+                pix = if cgb { cgb_color(&gb_mem.obj_pal, o.pal, o.color) } else { self.get_color(gb_mem, o.color, OBJPALBP + o.pal as u16) };
             }
         }
+        let ly = self.line() as usize;
+        self.screen[ly*WIDTH + self.fifo.lx as usize] = pix;
+        self.fifo.lx += 1;
+        return self.fifo.lx as usize >= WIDTH;
     }
 }
 
 pub fn gpu_cycle(gb_gpu: &mut Gpu, gb_mem: &mut Mem, clks: u64) {
-    if !gb_gpu.lcd_on(gb_mem) { return; }
-
-    gb_gpu.clk += clks - gb_gpu.prev;
+    let dots = clks - gb_gpu.prev;
     gb_gpu.prev = clks;
+    let on = gb_mem.read(LCD_CTLP) & 0x80 != 0;
+    if on != gb_gpu.lcd {
+        gb_gpu.lcd = on;
+        if on {
+            gb_gpu.lcd_on(gb_mem);
+        } else {
+            gb_gpu.lcd_off(gb_mem);
+        }
+    }
+    if !on {
+        gb_mem.stat_write = false;
+        return;
+    }
 
+    for _ in 0..dots {
+        gpu_dot(gb_gpu, gb_mem);
+        gb_gpu.update_stat(gb_mem);
+    }
+}
+
+fn gpu_dot(gb_gpu: &mut Gpu, gb_mem: &mut Mem) {
     match gb_gpu.mode {
-        GpuMode::HBLANK => {
-            if gb_gpu.clk >= 204 {
-                if gb_gpu.line(gb_mem) == 143 {
-                    gb_gpu.set_mode(gb_mem, GpuMode::VBLANK);
-                    gb_gpu.front.render();
-                    gb_mem.write(PINT_F, gb_mem.read(PINT_F) | 0x1);
-                } else {
-                    gb_gpu.set_mode(gb_mem, GpuMode::OAM);
+        GpuMode::OAM => {
+            if gb_gpu.dot == 0 {
+                gb_gpu.oam_scan(gb_mem);
+                if gb_mem.read(WYP) == gb_gpu.line() {
+                    gb_gpu.wy_hit = true;
                 }
-                gb_gpu.set_line(gb_mem, gb_gpu.line(gb_mem) + 1);
-                gb_gpu.clk -= 204;
+            }
+            if gb_gpu.dot == OAM_DOTS - 1 {
+                gb_gpu.set_mode(GpuMode::VRAM);
+                gb_gpu.lcd_start = false;
+                gb_gpu.start_mode3(gb_mem);
             }
         },
-        GpuMode::VBLANK => {
-            if gb_gpu.clk >= 456 {
-                gb_gpu.clk -= 456;
-                gb_gpu.set_line(gb_mem, gb_gpu.line(gb_mem) + 1);
-                if gb_gpu.line(gb_mem) > 153 {
-                    gb_gpu.set_line(gb_mem, 0);
-                    gb_gpu.set_mode(gb_mem, GpuMode::OAM);
+        GpuMode::VRAM => {
+            if gb_gpu.mode3_dot(gb_mem) {
+                if gb_gpu.fifo.window {
+                    gb_gpu.wline += 1;
                 }
+                gb_gpu.set_mode(GpuMode::HBLANK);
+                gb_mem.hblank = true;
             }
         },
-        GpuMode::OAM => {
-            if gb_gpu.clk >= 80 {
-                gb_gpu.clk -= 80;
-                gb_gpu.set_mode(gb_mem, GpuMode::VRAM);
+        GpuMode::HBLANK | GpuMode::VBLANK => {}
+    }
+
+    gb_gpu.dot += 1;
+    // LY goes to 0 a few dots into line 153, and LY=LYC compares with that
+    if gb_gpu.ly == 153 && gb_gpu.dot == 4 {
+        gb_mem.io[0x44] = 0;
+    }
+    if gb_gpu.dot < LINE_DOTS {
+        return;
+    }
+    gb_gpu.dot = 0;
+    let ly = gb_gpu.line();
+    match ly {
+        143 => {
+            gb_gpu.set_line(gb_mem, 144);
+            gb_gpu.set_mode(GpuMode::VBLANK);
+            if !gb_gpu.skip_frame {
+                gb_gpu.frame = gb_gpu.screen;
             }
-        },
-        GpuMode::VRAM => {
-            if gb_gpu.clk >= 172 {
-                gb_gpu.clk -= 172;
-                gb_gpu.set_mode(gb_mem, GpuMode::HBLANK);
-                gb_gpu.draw_line(gb_mem);
+            gb_gpu.skip_frame = false;
+            gb_gpu.frame_ready = true;
+            gb_gpu.frame_no += 1;
+            gb_gpu.wy_hit = false;
+            gb_gpu.wline = 0;
+            gb_gpu.wx166 = false;
+            gb_mem.write(PINT_F, gb_mem.read(PINT_F) | 0x1);
+        }
+        153 => {
+            gb_gpu.set_line(gb_mem, 0);
+            gb_gpu.set_mode(GpuMode::OAM);
+        }
+        _ if ly < 143 => {
+            gb_gpu.set_line(gb_mem, ly + 1);
+            gb_gpu.set_mode(GpuMode::OAM);
+        }
+        _ => gb_gpu.set_line(gb_mem, ly + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Power on state with the LCD, background and sprites on
+    fn setup() -> (Gpu, Mem) {
+        let mut mem = Mem::default();
+        mem.write(LCD_CTLP, 0x93);
+        mem.write(BG_PALLP, 0xE4);
+        return (Gpu::default(), mem);
+    }
+
+    fn run(gpu: &mut Gpu, mem: &mut Mem, dots: u64) {
+        let clk = gpu.prev + dots;
+        gpu_cycle(gpu, mem, clk);
+    }
+
+    fn mode(mem: &Mem) -> u8 {
+        return mem.read(GPU_INTS) & 3;
+    }
+
+    // Dots line 0 spends in mode 3, with 8x8 sprites on it at xs
+    fn mode3_len(scx: u8, xs: &[u8]) -> u16 {
+        let (mut gpu, mut mem) = setup();
+        mem.write(SCXP, scx);
+        for (i, x) in xs.iter().enumerate() {
+            mem.write(SPRITE_BASE + i as u16 * 4, 16);
+            mem.write(SPRITE_BASE + i as u16 * 4 + 1, *x);
+        }
+        let mut n = 0;
+        for _ in 0..LINE_DOTS {
+            run(&mut gpu, &mut mem, 1);
+            n += (mode(&mem) == 3) as u16;
+        }
+        return n;
+    }
+
+    // 172 dots, plus SCX fine scroll, plus 6 per sprite and up to 5 more for
+    // the first sprite on a tile, depending on where in the tile it starts
+    #[test]
+    fn mode3_length() {
+        assert_eq!(mode3_len(0, &[]), 172);
+        assert_eq!(mode3_len(3, &[]), 175);
+        assert_eq!(mode3_len(7, &[]), 179);
+        assert_eq!(mode3_len(8, &[]), 172);
+        assert_eq!(mode3_len(0, &[0]), 172 + 11);
+        assert_eq!(mode3_len(0, &[8]), 172 + 11);
+        assert_eq!(mode3_len(0, &[13]), 172 + 6);
+        assert_eq!(mode3_len(3, &[8]), 172 + 3 + 8); // SCX moves the sprite within its tile
+        assert_eq!(mode3_len(0, &[8, 10]), 172 + 11 + 6); // same tile
+        assert_eq!(mode3_len(0, &[8, 16]), 172 + 11 + 11);
+        assert_eq!(mode3_len(0, &[8; 10]), 172 + 11 + 9*6);
+        assert_eq!(mode3_len(0, &[8; 11]), 172 + 11 + 9*6); // only 10 per line
+        assert_eq!(mode3_len(0, &[168]), 172); // off screen
+    }
+
+    #[test]
+    fn mode3_window() {
+        let (mut gpu, mut mem) = setup();
+        mem.write(LCD_CTLP, 0xB3);
+        mem.write(WYP, 0);
+        mem.write(WXP, 87);
+        let mut n = 0;
+        for _ in 0..LINE_DOTS {
+            run(&mut gpu, &mut mem, 1);
+            n += (mode(&mem) == 3) as u16;
+        }
+        assert_eq!(n, 172 + 6);
+    }
+
+    // Run n dots, true if STAT requested an interrupt on the way
+    fn stat_irq(gpu: &mut Gpu, mem: &mut Mem, dots: u64) -> bool {
+        mem.write(PINT_F, 0);
+        run(gpu, mem, dots);
+        return mem.read(PINT_F) & 0x2 != 0;
+    }
+
+    #[test]
+    fn stat_blocking() {
+        // HBlank into the next line's OAM scan, the line never drops so mode 2 doesn't fire
+        let (mut gpu, mut mem) = setup();
+        mem.write(LYCP, 0x99);
+        mem.write(GPU_INTS, 0x28);
+        mem.stat_write = false;
+        assert!(stat_irq(&mut gpu, &mut mem, 1)); // line 0 mode 2
+        assert!(!stat_irq(&mut gpu, &mut mem, 78));
+        assert!(!stat_irq(&mut gpu, &mut mem, 172)); // mode 3
+        assert!(stat_irq(&mut gpu, &mut mem, 1)); // mode 0
+        assert!(!stat_irq(&mut gpu, &mut mem, 203));
+        assert!(!stat_irq(&mut gpu, &mut mem, 1)); // line 1 mode 2, blocked
+        assert_eq!((mem.read(SCLINEP), mode(&mem)), (1, 2));
+        assert!(!stat_irq(&mut gpu, &mut mem, 251));
+        assert!(stat_irq(&mut gpu, &mut mem, 1)); // mode 0 again after mode 3 dropped the line
+
+        // LY=LYC holds the line from mode 2 through mode 0
+        let (mut gpu, mut mem) = setup();
+        mem.write(LYCP, 1);
+        mem.write(GPU_INTS, 0x48);
+        mem.stat_write = false;
+        assert!(!stat_irq(&mut gpu, &mut mem, 1));
+        assert!(stat_irq(&mut gpu, &mut mem, 251)); // line 0 mode 0
+        assert!(!stat_irq(&mut gpu, &mut mem, 203));
+        assert!(!stat_irq(&mut gpu, &mut mem, 1)); // line 1, LY=LYC while mode 0 still holds it
+        assert!(!stat_irq(&mut gpu, &mut mem, 252)); // and mode 0 while LY=LYC does
+        assert_eq!(mode(&mem), 0);
+        assert!(!stat_irq(&mut gpu, &mut mem, 203));
+        assert!(!stat_irq(&mut gpu, &mut mem, 1)); // line 2 mode 2, not enabled
+        assert!(stat_irq(&mut gpu, &mut mem, 252));
+    }
+
+    #[test]
+    fn ly_153() {
+        let (mut gpu, mut mem) = setup();
+        mem.write(LYCP, 0);
+        mem.write(GPU_INTS, 0x40);
+        mem.stat_write = false;
+        run(&mut gpu, &mut mem, 153 * LINE_DOTS as u64);
+        assert_eq!(mem.read(SCLINEP), 153);
+        assert_eq!(mode(&mem), 1);
+        assert!(!stat_irq(&mut gpu, &mut mem, 3));
+        assert_eq!(mem.read(SCLINEP), 153);
+        assert_eq!(mem.read(GPU_INTS) & 0x4, 0);
+        // LY reads 0 from the 4th dot, LY=LYC sees that
+        assert!(stat_irq(&mut gpu, &mut mem, 1));
+        assert_eq!(mem.read(SCLINEP), 0);
+        assert_eq!(mem.read(GPU_INTS) & 0x7, 0x5);
+        // Line 0 keeps it high, no second interrupt
+        assert!(!stat_irq(&mut gpu, &mut mem, LINE_DOTS as u64 - 4));
+        assert_eq!((mem.read(SCLINEP), mode(&mem)), (0, 2));
+        assert_eq!(mem.read(GPU_INTS) & 0x4, 0x4);
+
+        // LYC 153 only matches for those first dots
+        let (mut gpu, mut mem) = setup();
+        mem.write(LYCP, 153);
+        run(&mut gpu, &mut mem, 153 * LINE_DOTS as u64 + 3);
+        assert_eq!(mem.read(GPU_INTS) & 0x4, 0x4);
+        run(&mut gpu, &mut mem, 1);
+        assert_eq!(mem.read(GPU_INTS) & 0x4, 0);
+    }
+
+    // Tile 0, which the whole background map points at, in color 3
+    fn fill_bg(mem: &mut Mem) {
+        for a in 0x8000..0x8010 {
+            mem.write(a, 0xFF);
+        }
+    }
+
+    // Every pixel of tile n in color c
+    fn solid(mem: &mut Mem, n: u16, c: u8) {
+        for a in 0..8 {
+            mem.write(0x8000 + n*16 + a*2, if c & 1 != 0 { 0xFF } else { 0x00 });
+            mem.write(0x8000 + n*16 + a*2 + 1, if c & 2 != 0 { 0xFF } else { 0x00 });
+        }
+    }
+
+    fn sprite(mem: &mut Mem, i: u16, y: u8, x: u8, tile: u8, attr: u8) {
+        for (k, v) in [y, x, tile, attr].iter().enumerate() {
+            mem.write(SPRITE_BASE + i*4 + k as u16, *v);
+        }
+    }
+
+    fn line(gpu: &Gpu, ly: usize) -> &[u16] {
+        return &gpu.frame[ly*WIDTH..(ly + 1)*WIDTH];
+    }
+
+    fn run_frame(gpu: &mut Gpu, mem: &mut Mem) {
+        run(gpu, mem, 154 * LINE_DOTS as u64);
+        assert!(gpu.take_frame().is_some());
+    }
+
+    #[test]
+    fn bg_off_is_white() {
+        let (mut gpu, mut mem) = setup();
+        fill_bg(&mut mem);
+        mem.write(BG_PALLP, 0xE7); // color 0 is black too
+        run_frame(&mut gpu, &mut mem);
+        assert!(gpu.frame.iter().all(|p| *p == 3));
+        mem.write(LCD_CTLP, 0x92);
+        run_frame(&mut gpu, &mut mem);
+        assert!(gpu.frame.iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn lcd_on() {
+        let (mut gpu, mut mem) = setup();
+        fill_bg(&mut mem);
+        run_frame(&mut gpu, &mut mem);
+        assert!(gpu.frame.iter().all(|p| *p == 3));
+
+        // Off, LY and the mode go to 0 and the screen is white
+        run(&mut gpu, &mut mem, 100);
+        mem.write(LCD_CTLP, 0x13);
+        run(&mut gpu, &mut mem, 1);
+        assert_eq!((mem.read(SCLINEP), mode(&mem)), (0, 0));
+        assert!(gpu.take_frame().unwrap().iter().all(|p| *p == 0));
+        run(&mut gpu, &mut mem, 1000);
+        assert_eq!((mem.read(SCLINEP), mode(&mem)), (0, 0));
+
+        // On, the first line starts 4 dots in and shows mode 0 instead of 2
+        mem.write(LCD_CTLP, 0x93);
+        run(&mut gpu, &mut mem, 0);
+        for _ in 0..75 {
+            run(&mut gpu, &mut mem, 1);
+            assert_eq!((mem.read(SCLINEP), mode(&mem)), (0, 0));
+        }
+        run(&mut gpu, &mut mem, 1);
+        assert_eq!(mode(&mem), 3);
+        run(&mut gpu, &mut mem, 172);
+        assert_eq!(mode(&mem), 0);
+        run(&mut gpu, &mut mem, 203);
+        assert_eq!((mem.read(SCLINEP), mode(&mem)), (0, 0));
+        run(&mut gpu, &mut mem, 1);
+        assert_eq!((mem.read(SCLINEP), mode(&mem)), (1, 2));
+
+        // The frame drawn then isn't shown, the next one is
+        run(&mut gpu, &mut mem, 143 * LINE_DOTS as u64);
+        assert!(gpu.take_frame().unwrap().iter().all(|p| *p == 0));
+        run_frame(&mut gpu, &mut mem);
+        assert!(gpu.frame.iter().all(|p| *p == 3));
+    }
+
+    // Pixel x goes out 12 + x dots into mode 3 and reads BGP then. SCX is read
+    // as each tile is fetched, a tile ahead of the pixels going out
+    #[test]
+    fn mode3_writes() {
+        let (mut gpu, mut mem) = setup();
+        fill_bg(&mut mem);
+        run(&mut gpu, &mut mem, 80 + 12 + 40);
+        mem.write(BG_PALLP, 0x1B);
+        run_frame(&mut gpu, &mut mem);
+        assert_eq!(line(&gpu, 0)[..40], [3; 40]);
+        assert_eq!(line(&gpu, 0)[40..], [0; 120]);
+        assert_eq!(line(&gpu, 1), [0; WIDTH]);
+
+        // Map columns in colors 0-3. The tile for pixels 48-55 is fetched 53 dots in
+        for (dots, c) in [(53, 3), (54, 2)] {
+            let (mut gpu, mut mem) = setup();
+            for n in 0..4 {
+                solid(&mut mem, n, n as u8);
+            }
+            for x in 0..32 {
+                mem.write(0x9800 + x, x as u8 % 4);
             }
+            run(&mut gpu, &mut mem, 80 + dots);
+            mem.write(SCXP, 8);
+            run_frame(&mut gpu, &mut mem);
+            assert_eq!(line(&gpu, 0)[32..56], [[0; 8], [1; 8], [c; 8]].concat());
+            assert_eq!(line(&gpu, 1)[..16], [[1; 8], [2; 8]].concat());
+        }
+    }
+
+    #[test]
+    fn sprites_8x16() {
+        let (mut gpu, mut mem) = setup();
+        mem.write(LCD_CTLP, 0x97);
+        mem.write(OBJPALBP, 0xE4);
+        solid(&mut mem, 4, 1);
+        solid(&mut mem, 5, 2);
+        mem.write(0x8040, 0xFF); // row 0 of tile 4 in color 3
+        mem.write(0x8041, 0xFF);
+        // Tile bit 0 is ignored, 5 draws 4 then 5. Y flip swaps the tiles too
+        sprite(&mut mem, 0, 16, 8, 5, 0x00);
+        sprite(&mut mem, 1, 16, 48, 4, 0x40);
+        run_frame(&mut gpu, &mut mem);
+        let col = |x: usize| (0..17).map(|ly| line(&gpu, ly)[x]).collect::<Vec<u16>>();
+        assert_eq!(col(0), [vec![3], vec![1; 7], vec![2; 8], vec![0]].concat());
+        assert_eq!(col(40), [vec![2; 8], vec![1; 7], vec![3], vec![0]].concat());
+    }
+
+    #[test]
+    fn sprite_priority() {
+        let (mut gpu, mut mem) = setup();
+        mem.write(OBJPALBP, 0xE4);
+        solid(&mut mem, 1, 1);
+        solid(&mut mem, 2, 2);
+        mem.write(0x9800, 1);
+        mem.write(0x9802, 1);
+        // Behind the background, only over its color 0. In front, over everything
+        sprite(&mut mem, 0, 16, 8, 2, 0x80);
+        sprite(&mut mem, 1, 16, 16, 2, 0x80);
+        sprite(&mut mem, 2, 16, 24, 2, 0x00);
+        run_frame(&mut gpu, &mut mem);
+        assert_eq!(line(&gpu, 0)[..32], [[1; 8], [2; 8], [2; 8], [0; 8]].concat());
+    }
+
+    #[test]
+    fn sprite_transparency() {
+        let (mut gpu, mut mem) = setup();
+        fill_bg(&mut mem);
+        mem.write(OBJPALBP, 0xE4);
+        solid(&mut mem, 1, 1);
+        solid(&mut mem, 2, 2);
+        for a in (0x8010..0x8020).step_by(2) {
+            mem.write(a, 0xF0); // tile 1's right half is color 0
+        }
+        // Color 0 shows what's under it, the background or a sprite that lost
+        sprite(&mut mem, 0, 16, 8, 1, 0x00);
+        sprite(&mut mem, 1, 16, 12, 2, 0x00);
+        sprite(&mut mem, 2, 16, 40, 1, 0x00);
+        run_frame(&mut gpu, &mut mem);
+        assert_eq!(line(&gpu, 0)[..16], [[1; 4], [2; 4], [2; 4], [3; 4]].concat());
+        assert_eq!(line(&gpu, 0)[32..40], [[1; 4], [3; 4]].concat());
+    }
+
+    #[test]
+    fn sprite_x_ties() {
+        let (mut gpu, mut mem) = setup();
+        mem.write(OBJPALBP, 0xE4);
+        solid(&mut mem, 1, 1);
+        solid(&mut mem, 2, 2);
+        // The lower X wins whatever the OAM order, at the same X the lower index does
+        sprite(&mut mem, 0, 16, 12, 1, 0x00);
+        sprite(&mut mem, 1, 16, 8, 2, 0x00);
+        sprite(&mut mem, 2, 16, 40, 1, 0x00);
+        sprite(&mut mem, 3, 16, 40, 2, 0x00);
+        sprite(&mut mem, 4, 16, 72, 2, 0x00);
+        sprite(&mut mem, 5, 16, 72, 1, 0x00);
+        run_frame(&mut gpu, &mut mem);
+        assert_eq!(line(&gpu, 0)[..12], [vec![2; 8], vec![1; 4]].concat());
+        assert_eq!(line(&gpu, 0)[32..40], [1; 8]);
+        assert_eq!(line(&gpu, 0)[64..72], [2; 8]);
+    }
+
+    // Window on at WY 0 from map 0x9C00, tiles 1-3 in their colors
+    fn window(mem: &mut Mem, wx: u8) {
+        mem.write(LCD_CTLP, 0xF3);
+        mem.write(WYP, 0);
+        mem.write(WXP, wx);
+        for n in 1..4 {
+            solid(mem, n, n as u8);
+        }
+    }
+
+    #[test]
+    fn window_line() {
+        let (mut gpu, mut mem) = setup();
+        fill_bg(&mut mem);
+        window(&mut mem, 7);
+        for x in 0..32 {
+            mem.write(0x9C00 + x, 1);
+            mem.write(0x9C20 + x, 2);
+        }
+        // Lines it isn't drawn on, turned off or with WX past the screen, don't
+        // move it on to its next line
+        for ly in 0..24 {
+            mem.write(LCD_CTLP, if (4..8).contains(&ly) { 0xD3 } else { 0xF3 });
+            mem.write(WXP, if (8..12).contains(&ly) { 200 } else { 7 });
+            run(&mut gpu, &mut mem, LINE_DOTS as u64);
+        }
+        run(&mut gpu, &mut mem, 130 * LINE_DOTS as u64);
+        assert!(gpu.take_frame().is_some());
+        let col: Vec<u16> = (0..24).map(|ly| line(&gpu, ly)[0]).collect();
+        assert_eq!(col, [[1; 4], [3; 4], [3; 4], [1; 4], [2; 4], [2; 4]].concat());
+    }
+
+    #[test]
+    fn window_wx0() {
+        // WX 0 starts it 7 pixels in, and SCX fine scroll takes that many more
+        for (scx, wx, out) in [(0, 7, vec![1; 8]), (0, 0, [vec![1], vec![2; 7]].concat()), (3, 0, [vec![2; 6], vec![3; 2]].concat())] {
+            let (mut gpu, mut mem) = setup();
+            window(&mut mem, wx);
+            mem.write(SCXP, scx);
+            for x in 0..3 {
+                mem.write(0x9C00 + x, x as u8 + 1);
+            }
+            run_frame(&mut gpu, &mut mem);
+            assert_eq!(line(&gpu, 0)[..8], out[..], "SCX {} WX {}", scx, wx);
+        }
+    }
+
+    #[test]
+    fn window_wx166() {
+        let (mut gpu, mut mem) = setup();
+        fill_bg(&mut mem);
+        window(&mut mem, 166);
+        for a in 0x9C00..0xA000 {
+            mem.write(a, 1);
+        }
+        // Its first pixel is the line's last, and it carries on over all of the next line
+        run_frame(&mut gpu, &mut mem);
+        for ly in [0, 2, 4] {
+            assert_eq!(line(&gpu, ly)[..159], [3; 159]);
+            assert_eq!(line(&gpu, ly)[159], 1);
+            assert_eq!(line(&gpu, ly + 1), [1; WIDTH]);
+        }
+    }
+
+    // CGB mode. BG palette p color c is p*4 + c, the OBJ ones 0x100 more,
+    // written a color after another with BCPS/OCPS counting up
+    fn setup_cgb() -> (Gpu, Mem) {
+        let (gpu, mut mem) = setup();
+        std::rc::Rc::make_mut(&mut mem.rom)[0x143] = 0x80;
+        mem.power_on(Model::Cgb);
+        mem.write(0xFF68, 0x80);
+        mem.write(0xFF6A, 0x80);
+        for i in 0..32u16 {
+            mem.write(0xFF69, i as u8);
+            mem.write(0xFF69, 0);
+            mem.write(0xFF6B, i as u8);
+            mem.write(0xFF6B, 1);
+        }
+        return (gpu, mem);
+    }
+
+    fn bg(p: u16, c: u16) -> u16 {
+        return 0x8000 | (p*4 + c);
+    }
+
+    fn obj(p: u16, c: u16) -> u16 {
+        return 0x8100 | (p*4 + c);
+    }
+
+    #[test]
+    fn palette_ram() {
+        let (_, mut mem) = setup_cgb();
+        // 64 writes went all the way round
+        assert_eq!((mem.read(0xFF68), mem.read(0xFF6A)), (0x80, 0x80));
+        assert_eq!((mem.bg_pal[62], mem.obj_pal[62], mem.obj_pal[63]), (31, 31, 1));
+        // Reads don't count up
+        mem.write(0xFF68, 0x86);
+        assert_eq!((mem.read(0xFF69), mem.read(0xFF69), mem.read(0xFF68)), (3, 3, 0x86));
+        mem.write(0xFF69, 0x55);
+        assert_eq!((mem.read(0xFF68), mem.read(0xFF69)), (0x87, 0));
+        // Writes only do with bit 7
+        mem.write(0xFF6A, 0x3F);
+        mem.write(0xFF6B, 0x12);
+        mem.write(0xFF6B, 0x34);
+        assert_eq!((mem.read(0xFF6A), mem.read(0xFF6B)), (0x3F, 0x34));
+        mem.write(0xFF6A, 0xBF);
+        mem.write(0xFF6B, 0x77);
+        assert_eq!(mem.read(0xFF6A), 0x80);
+        assert_eq!((mem.bg_pal[6], mem.obj_pal[63]), (0x55, 0x77));
+    }
+
+    #[test]
+    fn cgb_attributes() {
+        let (mut gpu, mut mem) = setup_cgb();
+        solid(&mut mem, 1, 1);
+        mem.write(0xFF4F, 1);
+        solid(&mut mem, 1, 2);
+        mem.write(0x8020, 0x80); // tile 2 in bank 1 is only its top left pixel, in color 3
+        mem.write(0x8021, 0x80);
+        // Bank 0, bank 1, bank 1 palette 5, then X, Y and both flips
+        for (x, a) in [0x00, 0x08, 0x0D, 0x28, 0x48, 0x68].iter().enumerate() {
+            mem.write(0x9800 + x as u16, *a);
+        }
+        mem.write(0xFF4F, 0);
+        for x in 0..6 {
+            mem.write(0x9800 + x, if x < 3 { 1 } else { 2 });
+        }
+        // Sprites have a bank, palette and flips too
+        sprite(&mut mem, 0, 16, 64, 2, 0x2B);
+        run_frame(&mut gpu, &mut mem);
+
+        assert_eq!(line(&gpu, 0)[..24], [[bg(0, 1); 8], [bg(0, 2); 8], [bg(5, 2); 8]].concat());
+        let lit: Vec<(usize, usize)> = (0..8).flat_map(|y| (24..64).map(move |x| (x, y)))
+            .filter(|(x, y)| line(&gpu, *y)[*x] != bg(0, 0)).collect();
+        assert_eq!(lit, [(31, 0), (63, 0), (32, 7), (47, 7)]);
+        assert_eq!((line(&gpu, 0)[31], line(&gpu, 0)[63], line(&gpu, 7)[32]), (bg(0, 3), obj(3, 3), bg(0, 3)));
+    }
+
+    #[test]
+    fn cgb_priority() {
+        let (mut gpu, mut mem) = setup_cgb();
+        solid(&mut mem, 1, 1);
+        solid(&mut mem, 2, 2);
+        // Attribute bit 7 puts colors 1-3 over sprites, and so does OAM bit 7
+        mem.write(0xFF4F, 1);
+        mem.write(0x9800, 0x80);
+        mem.write(0x9801, 0x80);
+        mem.write(0xFF4F, 0);
+        for (x, t) in [1, 0, 1, 1].iter().enumerate() {
+            mem.write(0x9800 + x as u16, *t);
+        }
+        for x in 0..4 {
+            sprite(&mut mem, x, 16, 8 + x as u8 * 8, 2, if x == 3 { 0x80 } else { 0x00 });
+        }
+        run_frame(&mut gpu, &mut mem);
+        assert_eq!(line(&gpu, 0)[..32], [[bg(0, 1); 8], [obj(0, 2); 8], [obj(0, 2); 8], [bg(0, 1); 8]].concat());
+
+        // LCDC bit 0 clear puts sprites over everything, the background still shows
+        mem.write(LCD_CTLP, 0x92);
+        run_frame(&mut gpu, &mut mem);
+        assert_eq!(line(&gpu, 0)[..40], [vec![obj(0, 2); 32], vec![bg(0, 0); 8]].concat());
+    }
+
+    #[test]
+    fn cgb_opri() {
+        // OAM order in CGB mode, lower X first like a DMG with OPRI bit 0 set
+        for (opri, c) in [(0, 1), (1, 2)] {
+            let (mut gpu, mut mem) = setup_cgb();
+            solid(&mut mem, 1, 1);
+            solid(&mut mem, 2, 2);
+            mem.write(0xFF6C, opri);
+            sprite(&mut mem, 0, 16, 12, 1, 0x00);
+            sprite(&mut mem, 1, 16, 8, 2, 0x00);
+            run_frame(&mut gpu, &mut mem);
+            assert_eq!(line(&gpu, 0)[..12], [[obj(0, 2); 4], [obj(0, c); 4], [obj(0, 1); 4]].concat(), "OPRI {}", opri);
         }
     }
 }
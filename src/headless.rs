@@ -0,0 +1,97 @@
+// Runs a rom with no window or sound, as fast as it goes, for tests and batch checks
+use crate::debugger::Expr;
+use crate::gb::Gb;
+use crate::mem::Model;
+use crate::opts::Opts;
+use crate::screenshot;
+use std::fs;
+
+// Exit status
+pub const OK: i32 = 0;
+pub const FAILED: i32 = 1; // --until never held, or the frame hash didn't match
+
+const FRAME_CLKS: u64 = 70224;
+const UNTIL_FRAMES: u64 = 3600; // --until without --frames or --cycles, a minute
+
+// FNV-1a of the pixels, stable across palettes and frontends. DMG shades
+// are hashed as one byte each, everything a CGB draws as two
+pub fn hash(frame: &[u16], model: Model) -> u64 {
+    let width = if model == Model::Cgb { 2 } else { 1 };
+    let mut h = 0xCBF29CE484222325u64;
+    for p in frame {
+        let bytes = p.to_le_bytes();
+        for b in &bytes[..width] {
+            h = (h ^ *b as u64).wrapping_mul(0x100000001B3);
+        }
+    }
+    return h;
+}
+
+pub fn run(gb: &mut Gb, opts: &Opts) -> Result<i32, String> {
+    let until = match &opts.until {
+        Some(s) => Some(Expr::parse(s, &gb.syms).map_err(|e| format!("--until: {}", e))?),
+        None => None
+    };
+    let frames = match (opts.frames, opts.cycles, &until) {
+        (None, None, None) => return Err("--headless needs --frames, --cycles or --until".to_string()),
+        (None, None, Some(_)) => Some(UNTIL_FRAMES),
+        (f, _, _) => f
+    };
+    let start = gb.cpu.clk;
+    let limit = start.saturating_add(frames.map_or(u64::MAX, |f| f.saturating_mul(FRAME_CLKS)).min(opts.cycles.unwrap_or(u64::MAX)));
+
+    let mut met = false;
+    while gb.cpu.clk < limit && gb.cpu.stop == 0 {
+        gb.step();
+        if until.as_ref().is_some_and(|e| e.eval(gb) != 0) {
+            met = true;
+            break;
+        }
+    }
+
+    let clks = gb.cpu.clk - start;
+    let why = if met {
+        format!("condition held at {}", gb.loc(gb.cpu.pc))
+    } else if gb.cpu.stop != 0 {
+        format!("STOP at {}", gb.loc(gb.cpu.pc.wrapping_sub(2)))
+    } else {
+        "limit reached".to_string()
+    };
+    let h = hash(&gb.gpu.frame, gb.mem.model);
+    println!("{} after {} frames ({} cycles), frame hash {:016x}", why, clks / FRAME_CLKS, clks, h);
+
+    if let Some(f) = &opts.png {
+        fs::write(f, screenshot::encode(gb, opts.shot_scale)).map_err(|e| format!("{}: {}", f, e))?;
+    }
+    // The whole address space as the cpu sees it, file offset = address
+    if let Some(f) = &opts.dump {
+        let dat: Vec<u8> = (0..=0xFFFF).map(|a| gb.mem.read(a)).collect();
+        fs::write(f, dat).map_err(|e| format!("{}: {}", f, e))?;
+    }
+
+    let mut status = OK;
+    if until.is_some() && !met {
+        println!("--until {} never held", opts.until.as_ref().unwrap());
+        status = FAILED;
+    }
+    if let Some(e) = &opts.expect_hash {
+        if u64::from_str_radix(e.trim_start_matches("0x"), 16) != Ok(h) {
+            println!("frame hash {:016x} doesn't match {}", h, e);
+            status = FAILED;
+        }
+    }
+    return Ok(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_width() {
+        // A CGB frame that's still all zero isn't the same as a DMG one
+        assert_ne!(hash(&[0; 4], Model::Dmg), hash(&[0; 4], Model::Cgb));
+        assert_eq!(hash(&[0; 4], Model::Cgb), hash(&[0; 8], Model::Dmg));
+        assert_eq!(hash(&[3, 0], Model::Dmg), hash(&[3], Model::Cgb));
+    }
+}
@@ -2,11 +2,15 @@ use crate::mem::Mem;
 use crate::consts::*;
 
 
+#[derive(Clone)]
 pub struct Input {
     r0: u8,
-    r1: u8
+    r1: u8,
+    pub log: Vec<(KeyCode, bool)>, // Key events not yet picked up by rewind
 }
 
+#[derive(Clone, Copy)]
+#[cfg_attr(not(any(feature = "sdl", feature = "minifb")), allow(dead_code))] // only frontends make these
 pub enum KeyCode {
     Start,
     Select,
@@ -22,6 +26,7 @@ pub enum KeyCode {
 
 impl Input {
     pub fn key(&mut self, gb_mem: &mut Mem, key: KeyCode, kp: bool) {
+        self.log.push((key, kp));
         match key {
             KeyCode::Start => self.update_row(START, kp, false),
             KeyCode::Select => self.update_row(SELECT, kp, false),
@@ -79,7 +84,8 @@ impl Default for Input {
     fn default() -> Input {
         Input {
             r0: 0x0F,
-            r1: 0x0F
+            r1: 0x0F,
+            log: Vec::new(),
         }
     }
 }
@@ -1,59 +1,264 @@
+mod asm;
+mod cdl;
 mod consts;
 mod cpu;
+mod debugger;
+mod disasm;
 mod frontend;
+mod gb;
+mod gdb;
+mod headless;
 mod input;
 mod gpu;
 mod mem;
+mod opts;
+mod png;
+mod profiler;
+mod record;
+mod rewind;
+mod romdis;
+mod screenshot;
+mod sst;
+mod symbols;
 mod timer;
 
 use std::io;
 use std::io::prelude::*;
 use std::fs::File;
 use std::io::SeekFrom;
+use std::rc::Rc;
 
 
 fn load_rom(gb_mem: &mut mem::Mem, rom: &str) -> io::Result<()> {
     let mut r = File::open(rom)?;
-    r.read_exact(&mut gb_mem.rom)?;
+    r.read_exact(&mut Rc::make_mut(&mut gb_mem.rom)[..])?;
     r.seek(SeekFrom::Start(16384))?;
-    r.read_exact(&mut gb_mem.rom_bank)?;
+    r.read_exact(&mut Rc::make_mut(&mut gb_mem.rom_bank)[..])?;
     return Ok(())
 }
 
+fn run_sst(args: &[String]) -> io::Result<()> {
+    let verbose = args.iter().any(|a| a == "-v");
+    let pos: Vec<&String> = args.iter().filter(|a| *a != "-v").collect();
+    if pos.is_empty() {
+        println!("{}", opts::USAGE);
+        std::process::exit(2);
+    }
+
+    match sst::run(pos[0], pos.get(1).map(|s| s.as_str()), verbose) {
+        Ok(true) => Ok(()),
+        Ok(false) => std::process::exit(1),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+// .cdl and .sym next to the rom are used when not given
+fn run_disasm(args: &[String]) -> io::Result<()> {
+    let err = |e: String| io::Error::other(e);
+    let (mut rom, mut out, mut cdl, mut sym) = (None, ".".to_string(), None, None);
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "-o" => out = it.next().ok_or(err("-o needs a directory".to_string()))?.clone(),
+            "--cdl" => cdl = it.next().cloned(),
+            "--sym" => sym = it.next().cloned(),
+            s => rom = Some(s.to_string()),
+        }
+    }
+    let rom = match rom {
+        Some(r) => r,
+        None => {
+            println!("{}", opts::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let path = std::path::Path::new(&rom);
+    let cdl = cdl.map(std::path::PathBuf::from).unwrap_or(path.with_extension("cdl"));
+    let sym = sym.map(std::path::PathBuf::from).unwrap_or(path.with_extension("sym"));
+
+    let dat = std::fs::read(path)?;
+    let log = if cdl.exists() {
+        let f = std::fs::read(&cdl)?;
+        Some(cdl::Cdl::parse(&f, dat.len(), png::crc32(&dat)).map_err(|e| err(format!("{}: {}", cdl.display(), e)))?)
+    } else {
+        None
+    };
+    let syms = if sym.exists() { symbols::Symbols::load(&sym).map_err(err)? } else { symbols::Symbols::default() };
+
+    let mut dis = romdis::Dis::new(dat, log, syms);
+    dis.run();
+    let name = path.file_name().map_or(rom.clone(), |n| n.to_string_lossy().into_owned());
+    return dis.write(std::path::Path::new(&out), &name).map_err(err);
+}
+
 fn main() -> io::Result<()> {
-    let mut gb_cpu = cpu::Cpu::default();
-    let mut gb_gpu = gpu::Gpu::default();
-    let mut gb_mem = mem::Mem::default();
-    let mut gb_input = input::Input::default();
-    let mut gb_timer = timer::Timer::default();
-    load_rom(&mut gb_mem, "test_roms/drmw.gb")?;
-    gb_mem.write(consts::CTLTTP, 3);
-    gb_mem.write(consts::JOYP, 255);
-    gb_exec(&mut gb_cpu, &mut gb_gpu, &mut gb_input, &mut gb_timer, &mut gb_mem).unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "sst" {
+        return run_sst(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "disasm" {
+        return run_disasm(&args[2..]);
+    }
+    let opts = match opts::Opts::parse(&args[1..]) {
+        Ok(o) => o,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut gb = gb::Gb::default();
+    let mut dbg = debugger::Debugger::default();
+    gb.hist = rewind::History::new(if opts.headless { 0 } else { opts.rewind });
+    load_rom(&mut gb.mem, &opts.rom)?;
+    // A is 0x11 after the CGB boot rom, which is how games tell
+    let model = opts.model.unwrap_or(if gb.mem.rom[0x143] & 0x80 != 0 { mem::Model::Cgb } else { mem::Model::Dmg });
+    gb.mem.power_on(model);
+    if model == mem::Model::Cgb {
+        gb.cpu.set_hilo(consts::A, consts::F, 0x1180);
+        gb.cpu.set_hilo(consts::B, consts::C, 0x0000);
+        let (de, hl) = if gb.mem.cgb { (0xFF56, 0x000D) } else { (0x0008, 0x007C) };
+        gb.cpu.set_hilo(consts::D, consts::E, de);
+        gb.cpu.set_hilo(consts::H, consts::L, hl);
+    }
+    let sym = std::path::Path::new(&opts.rom).with_extension("sym");
+    if sym.exists() {
+        match symbols::Symbols::load(&sym) {
+            Ok(s) => {
+                println!("loaded {} symbols from {}", s.len(), sym.display());
+                gb.syms = s;
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+    gb.mem.write(consts::CTLTTP, 3);
+    gb.mem.write(consts::JOYP, 255);
+    if opts.debug {
+        dbg.request_break();
+    }
+    if let Some(f) = &opts.cdl {
+        let crc = png::crc32(&std::fs::read(&opts.rom)?);
+        let c = cdl::Cdl::load(std::path::Path::new(f), gb.mem.rom_size(), crc).map_err(io::Error::other)?;
+        gb.mem.cdl = Some(std::rc::Rc::new(std::cell::RefCell::new(c)));
+    }
+    if opts.profile.is_some() {
+        gb.prof = Some(profiler::Profiler::new(&gb.cpu, &gb.mem));
+    }
+    if opts.headless {
+        // Errors exit 2, so they can't be mistaken for a failed check
+        let status = match headless::run(&mut gb, &opts) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        write_reports(&gb, &opts)?;
+        std::process::exit(status);
+    }
+    let mut gdb = match opts.gdb {
+        Some(port) => Some(gdb::Stub::listen(port)?),
+        None => None
+    };
+    let mut front = match frontend::open(opts.frontend.as_deref()) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut shots = screenshot::Screenshots::new(&opts.screenshots, opts.shot_scale);
+    let mut rec = record::Recorder::new(opts.record.as_deref(), opts.record_cmd.as_deref(), &opts.videos);
+    if opts.record.is_some() || opts.record_cmd.is_some() {
+        let to = rec.start(&gb.mem.rom_title()).map_err(io::Error::other)?;
+        println!("recording to {}", to);
+    }
+    gb_exec(&mut gb, &mut *front, &mut shots, &mut rec, &mut dbg, &mut gdb).unwrap();
+    rec.stop().map_err(io::Error::other)?;
+    return write_reports(&gb, &opts);
+}
+
+// Profile and code/data log, at exit
+fn write_reports(gb: &gb::Gb, opts: &opts::Opts) -> io::Result<()> {
+    if let (Some(p), Some(f)) = (&gb.prof, &opts.profile) {
+        p.report(&gb.mem, &gb.syms, opts.top);
+        p.write_collapsed(&mut io::BufWriter::new(File::create(f)?), &gb.syms)?;
+    }
+    if let (Some(c), Some(f)) = (&gb.mem.cdl, &opts.cdl) {
+        c.borrow().report();
+        c.borrow().save(std::path::Path::new(f)).map_err(io::Error::other)?;
+    }
     return Ok(())
 
 }
 
-fn gb_frame(gb_cpu: &mut cpu::Cpu, gb_gpu: &mut gpu::Gpu, gb_input: &mut input::Input, gb_timer: &mut timer::Timer, gb_mem: &mut mem::Mem) {
-    let target = gb_cpu.clk + 70224;
-    while gb_cpu.clk < target && gb_cpu.stop == 0 {
-        cpu::cpu_cycle(gb_cpu, gb_mem);
-        gpu::gpu_cycle(gb_gpu, gb_mem, gb_cpu.clk);
-        if gb_mem.input_update {
-            gb_input.update(gb_mem);
+fn gb_frame(gb: &mut gb::Gb, dbg: &mut debugger::Debugger, gdb: &mut Option<gdb::Stub>) {
+    let target = gb.cpu.clk + 70224;
+    while gb.cpu.clk < target && gb.cpu.stop == 0 {
+        if dbg.active() && dbg.check(gb) {
+            dbg.repl(gb);
         }
-        gb_timer.inc(gb_cpu.clk, gb_mem);
+        if let Some(g) = gdb {
+            g.check(gb);
+        }
+        if gb.cpu.stop != 0 {
+            break;
+        }
+        gb.step();
     }
 }
 
-fn gb_exec(gb_cpu: &mut cpu::Cpu, gb_gpu: &mut gpu::Gpu, gb_input: &mut input::Input, gb_timer: &mut timer::Timer, gb_mem: &mut mem::Mem) -> Result<(), String> {
+// Stereo silence up to the end of frame n, sampled is how many samples went out before
+fn silence(n: u64, sampled: &mut u64) -> Vec<i16> {
+    let total = n * frontend::SAMPLE_RATE as u64 * 70224 / 4194304;
+    let s = vec![0i16; (total - *sampled) as usize * 2];
+    *sampled = total;
+    return s;
+}
+
+fn gb_exec(gb: &mut gb::Gb, front: &mut dyn frontend::Frontend, shots: &mut screenshot::Screenshots, rec: &mut record::Recorder, dbg: &mut debugger::Debugger, gdb: &mut Option<gdb::Stub>) -> Result<(), String> {
+    let mut n = 0u64; // frames run, for pacing sound
+    let mut sampled = 0u64;
     let st = std::time::Instant::now();
-    while gb_cpu.stop == 0 {
-        gb_gpu.front.check_event(gb_cpu, gb_input, gb_mem);
-        gb_frame(gb_cpu, gb_gpu, gb_input, gb_timer, gb_mem);
-        gb_gpu.frames += 1.;
+    while gb.cpu.stop == 0 {
+        for ev in front.poll() {
+            match ev {
+                frontend::Event::Quit => gb.cpu.stop = 1,
+                frontend::Event::Key(k, down) => gb.input.key(&mut gb.mem, k, down),
+                frontend::Event::Hotkey(frontend::Hotkey::Debug) => dbg.request_break(),
+                frontend::Event::Hotkey(frontend::Hotkey::Screenshot) => match shots.save(gb) {
+                    Ok(p) => println!("saved {}", p.display()),
+                    Err(e) => println!("{}", e),
+                },
+                frontend::Event::Hotkey(frontend::Hotkey::Record) => {
+                    let res = if rec.active() {
+                        rec.stop().map(|_| "recording stopped".to_string())
+                    } else {
+                        rec.start(&gb.mem.rom_title()).map(|to| format!("recording to {}", to))
+                    };
+                    println!("{}", res.unwrap_or_else(|e| e));
+                }
+            }
+        }
+        if let Some(g) = gdb {
+            g.poll();
+        }
+        gb_frame(gb, dbg, gdb);
+        // No sound emulation yet, so it's silence, as many samples as this frame took.
+        // The recording gets a frame for every 70224 cycles to keep to emulated time, the
+        // last one again when none finished, like the white screen while the LCD is off
+        n += 1;
+        let sound = silence(n, &mut sampled);
+        front.queue_audio(&sound);
+        if let Some(f) = gb.gpu.take_frame() {
+            front.present(f);
+        }
+        if let Err(e) = rec.frame(&gb.gpu.frame).and_then(|_| rec.audio(&sound)) {
+            println!("{}", e);
+        }
+        gb.gpu.frames += 1.;
     }
     let ep = st.elapsed();
-    println!("{}", gb_gpu.frames/ep.as_secs_f64());
+    println!("{}", gb.gpu.frames/ep.as_secs_f64());
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,13 +1,78 @@
+use crate::cdl::Cdl;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+// Watchpoint kinds
+pub const WATCH_W: u8 = 1;
+pub const WATCH_R: u8 = 2;
+
+#[derive(Clone)]
+pub struct Watch {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: u8,
+}
+
+// Anything the cpu can run against
+pub trait Bus {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, val: u8);
+    // Bus cycles of instructions, clk is the cpu clock at the access
+    fn read_at(&self, _clk: u64, address: u16) -> u8 {
+        return self.read(address);
+    }
+    fn write_at(&mut self, _clk: u64, address: u16, val: u8) {
+        self.write(address, val);
+    }
+    // IE/IF for the interrupt poll and acknowledge. Not bus cycles, so no
+    // watchpoints or code/data log
+    fn peek(&self, address: u16) -> u8 {
+        return self.read(address);
+    }
+    fn put(&mut self, address: u16, val: u8) {
+        self.write(address, val);
+    }
+    fn bank_of(&self, _address: u16) -> u16 {
+        return 0;
+    }
+    // STOP with a CGB speed switch armed in KEY1 switches instead, true if it did
+    fn speed_switch(&mut self) -> bool {
+        return false;
+    }
+}
+
+// Hardware being emulated. A CGB runs DMG carts in compatibility mode
+#[derive(Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+#[derive(Clone)]
 pub struct Mem {
-    pub rom: [u8; 16384], // rom mem
-    pub rom_bank: [u8; 16384], // rom bank
-    vram: [u8; 8192], // video ram
+    pub rom: Rc<[u8; 16384]>, // rom mem, shared with rewind snapshots
+    pub rom_bank: Rc<[u8; 16384]>, // rom bank
+    vram: [u8; 16384], // video ram, bank 1 is CGB only
     exram: [u8; 8192], // external ram
-    wram: [u8; 8192], // work ram
+    wram: [u8; 32768], // work ram, banks 2-7 are CGB only
     sdata: [u8; 160], // sprite data
     pub io: [u8; 128], // I/O mem
     zero_pg: [u8; 128], // Zero Page
+    pub bg_pal: [u8; 64], // CGB palette ram, 8 palettes of 4 little endian 15 bit colors
+    pub obj_pal: [u8; 64],
+    pub model: Model,
+    pub cgb: bool, // CGB mode, a CGB running a cart that supports it
+    hdma_src: u16, // next HDMA/GDMA block, FF51-FF54 are only read when it starts
+    hdma_dst: u16,
+    hdma_on: bool, // HBlank DMA running, FF55 has the blocks left - 1
+    pub hblank: bool, // the gpu entered HBlank, time for an HDMA block
+    pub dma_stall: u64, // clocks the cpu waits for HDMA/GDMA copies
     pub input_update: bool, // Tell input to update joy io reg
+    pub stat_write: bool, // STAT was written, the gpu checks for the spurious interrupt
+    pub div_reset: bool, // DIV was reset, the timer restarts its count to the next tick
+    pub watches: Vec<Watch>, // Cpu access watchpoints
+    pub watch_hit: Cell<Option<(u16, u8)>>, // Last watchpoint hit, addr and the watch's kind
+    pub cdl: Option<Rc<RefCell<Cdl>>>, // Code/data log, shared with rewind snapshots
 }
 
 impl Mem {
@@ -16,24 +81,135 @@ impl Mem {
         return match addr {
             0x0000..=0x3FFF => self.rom[addr], // Rom
             0x4000..=0x7FFF => self.rom_bank[addr - 0x4000], // Rom Bank
-            0x8000..=0x9FFF => self.vram[addr - 0x8000], // Video Ram
+            0x8000..=0x9FFF => self.vram[self.vbk() + addr - 0x8000], // Video Ram
             0xA000..=0xBFFF => self.exram[addr - 0xA000], // External Ram
-            0xC000..=0xDFFF => self.wram[addr - 0xC000], // Work Ram
-            0xE000..=0xFDFF => self.wram[addr & 0x1FFF], // Work Ram copy
+            0xC000..=0xFDFF => self.wram[self.wram_index(address)], // Work Ram and its copy
             0xFE00..=0xFE9F => self.sdata[addr - 0xFE00], // Sprite Data/Object Mem
+            0xFF4D if self.cgb => self.io[0x4D] | 0x7E,
+            0xFF4F if self.cgb => self.io[0x4F] | 0xFE,
+            0xFF51..=0xFF54 if self.cgb => 0xFF,
+            0xFF69 if self.cgb => self.bg_pal[self.io[0x68] as usize & 0x3F],
+            0xFF6B if self.cgb => self.obj_pal[self.io[0x6A] as usize & 0x3F],
+            0xFF6C if self.model == Model::Cgb => self.io[0x6C] | 0xFE,
+            0xFF70 if self.cgb => self.io[0x70] | 0xF8,
+            0xFF70 => 0xFF,
+            0xFF72..=0xFF77 if self.model == Model::Dmg => 0xFF,
+            0xFF74 if self.model == Model::Cgb && !self.cgb => 0xFF,
+            0xFF75 if self.model == Model::Cgb => self.io[0x75] | 0x8F,
+            0xFF76 | 0xFF77 if self.model == Model::Cgb => 0, // PCM12/34, no sound yet so every channel is at 0
             0xFF00..=0xFF7F => self.io[addr - 0xFF00],// I/O 
             0xFF80..=0xFFFF => self.zero_pg[addr - 0xFF80], // Zero Page
             _ => 0 // Includes skipped FE10 to FEFF
         };
     }
 
+    // CPU at 8 MHz, KEY1 bit 7
+    pub fn double_speed(&self) -> bool {
+        return self.cgb && self.io[0x4D] & 0x80 != 0;
+    }
+
+    pub fn speed_switch(&mut self) -> bool {
+        if !self.cgb || self.io[0x4D] & 1 == 0 {
+            return false;
+        }
+        self.io[0x4D] = !self.io[0x4D] & 0x80;
+        self.io[4] = 0; // DIV resets
+        self.div_reset = true;
+        return true;
+    }
+
+    // Set up registers the way the boot rom leaves them. CGB mode needs the cart's CGB flag
+    pub fn power_on(&mut self, model: Model) {
+        self.model = model;
+        self.cgb = model == Model::Cgb && self.rom[0x143] & 0x80 != 0;
+        if model == Model::Cgb {
+            self.io[0x4C] = if self.cgb { 0x80 } else { 0x04 }; // KEY0, DMG compatibility is 0x04
+            self.io[0x6C] = !self.cgb as u8; // OPRI, compatibility mode keeps DMG X priority
+        }
+    }
+
+    // wram offset of a 0xC000-0xFDFF address. SVBK picks the bank at 0xD000, 0 means 1
+    fn wram_index(&self, address: u16) -> usize {
+        let a = address as usize & 0x1FFF;
+        if a < 0x1000 {
+            return a;
+        }
+        return self.wram_bank() as usize * 0x1000 + a - 0x1000;
+    }
+
+    fn wram_bank(&self) -> u16 {
+        return if self.cgb { (self.io[0x70] as u16 & 7).max(1) } else { 1 };
+    }
+
+    // Offset of the VRAM bank VBK selects
+    fn vbk(&self) -> usize {
+        return if self.cgb { (self.io[0x4F] as usize & 1) * 0x2000 } else { 0 };
+    }
+
+    // VRAM as the gpu sees it, either bank whatever VBK is
+    pub fn vram(&self, bank: u8, address: u16) -> u8 {
+        return self.vram[(bank as usize & 1) * 0x2000 + (address as usize & 0x1FFF)];
+    }
+
+    // Bank mapped at address, numbered like RGBDS does. No MBC yet so
+    // 0x4000-0x7FFF is always rom bank 1
+    pub fn bank_of(&self, address: u16) -> u16 {
+        return match address {
+            0x4000..=0x7FFF => 1,
+            0x8000..=0x9FFF => (self.vbk() / 0x2000) as u16,
+            0xD000..=0xDFFF => self.wram_bank(),
+            _ => 0
+        };
+    }
+
+    // Offset into the rom file, None outside rom
+    pub fn rom_offset(&self, address: u16) -> Option<usize> {
+        return match address {
+            0x0000..=0x3FFF => Some(address as usize),
+            0x4000..=0x7FFF => Some(self.bank_of(address) as usize * 0x4000 + (address as usize - 0x4000)),
+            _ => None
+        };
+    }
+
+    // Like write, but rom is writable too. Returns the rom offset if it patched rom
+    pub fn poke(&mut self, address: u16, val: u8) -> Option<usize> {
+        let off = self.rom_offset(address);
+        match address {
+            0x0000..=0x3FFF => Rc::make_mut(&mut self.rom)[address as usize] = val,
+            0x4000..=0x7FFF => Rc::make_mut(&mut self.rom_bank)[address as usize - 0x4000] = val,
+            _ => self.write(address, val)
+        }
+        return off;
+    }
+
+    // Byte at a rom file offset, 0 past the end
+    pub fn rom_byte(&self, off: usize) -> u8 {
+        return match off {
+            0x0000..=0x3FFF => self.rom[off],
+            0x4000..=0x7FFF => self.rom_bank[off - 0x4000],
+            _ => 0
+        };
+    }
+
+    // Cartridge header title, 0x134-0x143 up to the padding or the CGB flag
+    pub fn rom_title(&self) -> String {
+        let t: String = self.rom[0x134..0x144].iter()
+            .take_while(|b| **b != 0 && **b < 0x80)
+            .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '_' })
+            .collect();
+        return t.trim().to_string();
+    }
+
+    pub fn rom_size(&self) -> usize {
+        return self.rom.len() + self.rom_bank.len();
+    }
+
     pub fn write(&mut self, address: u16, val: u8) {
         let addr = address as usize;
         match addr {
-            0x8000..=0x9FFF => self.vram[addr - 0x8000] = val, // Video Ram
+            0x8000..=0x9FFF => self.vram[self.vbk() + addr - 0x8000] = val, // Video Ram
             0xA000..=0xBFFF => self.exram[addr - 0xA000] = val, // External Ram
-            0xC000..=0xDFFF => self.wram[addr - 0xC000] = val, // Work Ram
-            0xE000..=0xFDFF => self.wram[addr & 0x1FFF] = val, // Work Ram copy
+            0xC000..=0xFDFF => self.wram[self.wram_index(address)] = val, // Work Ram and its copy
             0xFE00..=0xFE9F => self.sdata[addr - 0xFE00] = val, // Sprite Data/Object Mem
             0xFF00 => {
                 self.io[0] = val;
@@ -42,13 +218,42 @@ impl Mem {
             0xFF01..=0xFF7F => {
                 if addr == 0xFF04 {
                     self.io[4] = 0; // divide timer reg
+                    self.div_reset = true;
                 } else if addr == 0xFF07 {
                     self.io[7] = val & 0x7;
+                } else if addr == 0xFF41 {
+                    self.io[0x41] = val & 0x78 | self.io[0x41] & 0x87; // mode and LY=LYC are read only
+                    self.stat_write = true;
+                } else if self.cgb && (addr == 0xFF69 || addr == 0xFF6B) {
+                    // Palette data at the index in BCPS/OCPS, which counts up if bit 7 is set
+                    let sel = addr - 0xFF01;
+                    let idx = self.io[sel] as usize & 0x3F;
+                    if addr == 0xFF69 { self.bg_pal[idx] = val } else { self.obj_pal[idx] = val }
+                    if self.io[sel] & 0x80 != 0 {
+                        self.io[sel] = 0x80 | (idx as u8 + 1) & 0x3F;
+                    }
+                } else if self.cgb && addr == 0xFF4D {
+                    self.io[0x4D] = self.io[0x4D] & 0x80 | val & 1; // bit 7 is the current speed
+                } else if (!self.cgb && addr == 0xFF70) || (self.model == Model::Dmg && matches!(addr, 0xFF72..=0xFF77)) {
+                    // SVBK is CGB mode only, and a DMG has none of these
+                } else if self.model == Model::Cgb && matches!(addr, 0xFF4C | 0xFF76 | 0xFF77) {
+                    // KEY0 locks when the boot rom finishes, PCM12/34 are read only
+                } else if self.model == Model::Cgb && addr == 0xFF6C {
+                    if self.cgb {
+                        self.io[0x6C] = val & 1;
+                    }
+                } else if self.cgb && addr == 0xFF70 {
+                    self.io[0x70] = val & 7;
+                } else if self.cgb && addr == 0xFF55 {
+                    self.hdma_start(val);
                 } else if addr == 0xFF46 {
                     self.io[0x46] = val;
                     for i in 0..160 {
                         self.sdata[i] = self.read(((val as u16) << 8) + i as u16) // OAM DMA
                     }
+                    if let Some(c) = self.cdl.clone() {
+                        c.borrow_mut().dma(self, (val as u16) << 8, 160);
+                    }
                 } else {
                     self.io[addr - 0xFF00] = val;
                 }
@@ -57,20 +262,128 @@ impl Mem {
             _ => return
         };
     }
+
+    // FF55 write. Bit 7 clear copies it all now (GDMA), unless that stops an
+    // HBlank DMA. Bit 7 set copies a block every HBlank
+    fn hdma_start(&mut self, val: u8) {
+        if self.hdma_on && val & 0x80 == 0 {
+            self.hdma_on = false;
+            self.io[0x55] |= 0x80; // what was left stays readable
+            return;
+        }
+        self.hdma_src = u16::from_be_bytes([self.io[0x51], self.io[0x52]]) & 0xFFF0;
+        self.hdma_dst = u16::from_be_bytes([self.io[0x53], self.io[0x54]]) & 0x1FF0 | 0x8000;
+        self.io[0x55] = val & 0x7F;
+        if val & 0x80 != 0 {
+            self.hdma_on = true;
+            return;
+        }
+        while self.io[0x55] != 0xFF {
+            self.hdma_block();
+        }
+    }
+
+    // One 16 byte block of HDMA/GDMA, the cpu is stopped 8 M-cycles for it,
+    // 16 in double speed. Running off the end of VRAM ends the transfer
+    fn hdma_block(&mut self) {
+        for i in 0..16 {
+            let b = self.read(self.hdma_src.wrapping_add(i));
+            self.write(self.hdma_dst + i, b);
+        }
+        if let Some(c) = self.cdl.clone() {
+            c.borrow_mut().dma(self, self.hdma_src, 16);
+        }
+        self.hdma_src = self.hdma_src.wrapping_add(16);
+        self.hdma_dst += 16;
+        self.dma_stall += 32;
+        self.io[0x55] = if self.hdma_dst == 0xA000 { 0xFF } else { self.io[0x55].wrapping_sub(1) };
+        if self.io[0x55] == 0xFF {
+            self.hdma_on = false;
+        }
+    }
+
+    // A block of HBlank DMA, if one is running
+    pub fn hdma_hblank(&mut self) {
+        if self.hdma_on {
+            self.hdma_block();
+        }
+    }
+
+    fn watch(&self, address: u16, kind: u8) {
+        for w in self.watches.iter() {
+            if w.kind & kind != 0 && address.wrapping_sub(w.addr) < w.len {
+                self.watch_hit.set(Some((address, w.kind)));
+            }
+        }
+    }
+}
+
+// Only the cpu goes through Bus, so this is where watchpoints and the code/data log are checked
+impl Bus for Mem {
+    fn read(&self, address: u16) -> u8 {
+        if !self.watches.is_empty() {
+            self.watch(address, WATCH_R);
+        }
+        let val = Mem::read(self, address);
+        if let Some(c) = &self.cdl {
+            c.borrow_mut().read(self, address, val);
+        }
+        return val;
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        if !self.watches.is_empty() {
+            self.watch(address, WATCH_W);
+        }
+        if let Some(c) = &self.cdl {
+            c.borrow_mut().write(address, val);
+        }
+        Mem::write(self, address, val);
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        return Mem::read(self, address);
+    }
+
+    fn put(&mut self, address: u16, val: u8) {
+        Mem::write(self, address, val);
+    }
+
+    fn speed_switch(&mut self) -> bool {
+        return Mem::speed_switch(self);
+    }
+
+    fn bank_of(&self, address: u16) -> u16 {
+        return Mem::bank_of(self, address);
+    }
 }
 
 impl Default for Mem {
     fn default() -> Mem {
         let mut m = Mem {
-            rom: [0; 16384],
-            rom_bank: [0; 16384],
-            vram: [0; 8192],
+            rom: Rc::new([0; 16384]),
+            rom_bank: Rc::new([0; 16384]),
+            vram: [0; 16384],
             exram: [0; 8192],
-            wram: [0; 8192],
+            wram: [0; 32768],
             sdata: [0; 160],
             io: [0; 128],
             zero_pg: [0; 128],
+            bg_pal: [0xFF; 64],
+            obj_pal: [0xFF; 64],
+            model: Model::Dmg,
+            cgb: false,
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_on: false,
+            hblank: false,
+            dma_stall: 0,
             input_update: false,
+            stat_write: false,
+            div_reset: false,
+            watches: Vec::new(),
+            watch_hit: Cell::new(None),
+            cdl: None,
         };
         m.io[0x10] = 0x80;
         m.io[0x11] = 0xBF;
@@ -91,6 +404,70 @@ impl Default for Mem {
         m.io[0x47] = 0xFC;
         m.io[0x48] = 0xFF;
         m.io[0x49] = 0xFF;
+        m.io[0x55] = 0xFF;
+        return m;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mem(model: Model, cgb_rom: bool) -> Mem {
+        let mut m = Mem::default();
+        Rc::make_mut(&mut m.rom)[0x143] = if cgb_rom { 0x80 } else { 0x00 };
+        m.power_on(model);
         return m;
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wram_banks() {
+        let mut m = mem(Model::Cgb, true);
+        for bank in 1..8 {
+            m.write(0xFF70, bank);
+            m.write(0xD000, 0x10 + bank);
+        }
+        // Bank 0 selects bank 1
+        m.write(0xFF70, 0);
+        assert_eq!((m.read(0xFF70), m.read(0xD000)), (0xF8, 0x11));
+        for bank in 1..8 {
+            m.write(0xFF70, bank);
+            assert_eq!(m.read(0xD000), 0x10 + bank);
+        }
+
+        // Echo RAM is the banked WRAM too
+        m.write(0xFF70, 5);
+        assert_eq!(m.read(0xF000), 0x15);
+        m.write(0xF001, 0xAB);
+        assert_eq!(m.read(0xD001), 0xAB);
+        m.write(0xFF70, 6);
+        assert_eq!(m.read(0xF001), 0x00);
+        m.write(0xE123, 0xCD);
+        assert_eq!(m.read(0xC123), 0xCD);
+    }
+
+    #[test]
+    fn cgb_registers_ignored() {
+        // DMG, DMG with a CGB cart, CGB in compatibility mode
+        for (model, cgb_rom) in [(Model::Dmg, false), (Model::Dmg, true), (Model::Cgb, false)] {
+            let mut m = mem(model, cgb_rom);
+            m.write(0xD000, 0x11);
+            m.write(0xFF70, 2);
+            assert_eq!(m.read(0xFF70), 0xFF);
+            assert_eq!(m.read(0xD000), 0x11);
+            m.write(0xD000, 0x22);
+            m.write(0xFF70, 0);
+            assert_eq!(m.read(0xD000), 0x22);
+            for addr in 0xFF72..=0xFF77 {
+                m.write(addr, 0x00);
+            }
+            let regs: Vec<u8> = (0xFF72..=0xFF77).map(|a| m.read(a)).collect();
+            if model == Model::Dmg {
+                assert_eq!(regs, [0xFF; 6]);
+            } else {
+                // FF72, FF73 and FF75 bits 4-6 work in compatibility mode too, FF74 doesn't
+                assert_eq!(regs, [0x00, 0x00, 0xFF, 0x8F, 0x00, 0x00]);
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::mem::Model;
+
+pub const USAGE: &str = "\
+usage: gameboy-emu [options] [rom]
+       gameboy-emu sst <dir> [opcode file prefix] [-v]
+       gameboy-emu disasm <rom> [-o <dir>] [--cdl <file>] [--sym <file>]
+
+options:
+    --debug             start in the debugger (F12 breaks in while running)
+    --gdb <port>        wait for a gdb remote connection on 127.0.0.1:port
+    --rewind <MB>       memory for reverse execution snapshots, 0 disables (default 32)
+    --profile <file>    profile cycles, writes flamegraph collapsed stacks to file on exit
+    --top <n>           hot spots and functions shown in the exit report (default 20)
+    --cdl <file>        log code/data use of every rom byte, merged into file on exit
+    --model <name>      dmg or cgb (default cgb for carts with the CGB flag, else dmg)
+    --frontend <name>   sdl or minifb, whichever were built in (default is the first)
+    --screenshots <dir> where F11 saves screenshots (default screenshots)
+    --shot-scale <n>    screenshot and --png pixel size (default 1)
+    --record <file.y4m> record from the start to file.y4m and file.wav, F10 toggles recording,
+                        numbering the later ones file_0002.y4m and on. Only Y4M is written
+    --record-cmd <cmd>  record by piping raw rgb24 frames into cmd's stdin instead, no sound,
+                        for other formats
+    --videos <dir>      where F10 records to without --record (default videos)
+
+headless, no window or sound, exits 0 or 1 if a check failed:
+    --headless          run without a frontend until one of these, --frames, --cycles or --until is required
+    --frames <n>        stop after n frames
+    --cycles <n>        stop after n cycles
+    --until <cond>      stop once cond != 0, debugger syntax, fails if it never happens.
+                        Alone it gives up after 3600 frames
+    --png <file>        save the last frame
+    --dump <file>       save the 64K address space
+    --expect-hash <h>   fail unless the last frame's hash is h";
+
+pub struct Opts {
+    pub rom: String,
+    pub model: Option<Model>,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub rewind: usize,
+    pub profile: Option<String>,
+    pub top: usize,
+    pub cdl: Option<String>,
+    pub frontend: Option<String>,
+    pub screenshots: String,
+    pub shot_scale: usize,
+    pub record: Option<String>,
+    pub record_cmd: Option<String>,
+    pub videos: String,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub cycles: Option<u64>,
+    pub until: Option<String>,
+    pub png: Option<String>,
+    pub dump: Option<String>,
+    pub expect_hash: Option<String>,
+}
+
+impl Default for Opts {
+    fn default() -> Opts {
+        Opts {
+            rom: "test_roms/drmw.gb".to_string(),
+            model: None,
+            debug: false,
+            gdb: None,
+            rewind: 32,
+            profile: None,
+            top: 20,
+            cdl: None,
+            frontend: None,
+            screenshots: "screenshots".to_string(),
+            shot_scale: 1,
+            record: None,
+            record_cmd: None,
+            videos: "videos".to_string(),
+            headless: false,
+            frames: None,
+            cycles: None,
+            until: None,
+            png: None,
+            dump: None,
+            expect_hash: None,
+        }
+    }
+}
+
+impl Opts {
+    pub fn parse(args: &[String]) -> Result<Opts, String> {
+        let mut opts = Opts::default();
+        let mut it = args.iter();
+        while let Some(a) = it.next() {
+            match a.as_str() {
+                "--debug" => opts.debug = true,
+                "--gdb" => {
+                    let port = it.next().ok_or("--gdb needs a port")?;
+                    opts.gdb = Some(port.parse().map_err(|_| format!("bad port {}", port))?);
+                }
+                "--rewind" => {
+                    let mb = it.next().ok_or("--rewind needs a size in MB")?;
+                    opts.rewind = mb.parse().map_err(|_| format!("bad size {}", mb))?;
+                }
+                "--profile" => {
+                    let f = it.next().ok_or("--profile needs an output file")?;
+                    opts.profile = Some(f.to_string());
+                }
+                "--top" => {
+                    let n = it.next().ok_or("--top needs a count")?;
+                    opts.top = n.parse().map_err(|_| format!("bad count {}", n))?;
+                }
+                "--cdl" => {
+                    let f = it.next().ok_or("--cdl needs a file")?;
+                    opts.cdl = Some(f.to_string());
+                }
+                "--model" => {
+                    opts.model = Some(match it.next().ok_or("--model needs dmg or cgb")?.as_str() {
+                        "dmg" => Model::Dmg,
+                        "cgb" => Model::Cgb,
+                        m => return Err(format!("unknown model {}, dmg or cgb", m)),
+                    });
+                }
+                "--frontend" => {
+                    let f = it.next().ok_or("--frontend needs a name")?;
+                    opts.frontend = Some(f.to_string());
+                }
+                "--screenshots" => {
+                    let d = it.next().ok_or("--screenshots needs a directory")?;
+                    opts.screenshots = d.to_string();
+                }
+                "--shot-scale" => {
+                    let n = it.next().ok_or("--shot-scale needs a scale")?;
+                    opts.shot_scale = n.parse().ok().filter(|n| *n > 0).ok_or(format!("bad scale {}", n))?;
+                }
+                "--record" => {
+                    let f = it.next().ok_or("--record needs a file")?;
+                    if std::path::Path::new(f).extension().is_none_or(|e| e != "y4m") {
+                        return Err(format!("--record only writes Y4M, {} isn't a .y4m file (--record-cmd can pipe into an encoder)", f));
+                    }
+                    opts.record = Some(f.to_string());
+                }
+                "--record-cmd" => {
+                    let c = it.next().ok_or("--record-cmd needs a command")?;
+                    opts.record_cmd = Some(c.to_string());
+                }
+                "--videos" => {
+                    let d = it.next().ok_or("--videos needs a directory")?;
+                    opts.videos = d.to_string();
+                }
+                "--headless" => opts.headless = true,
+                "--frames" => {
+                    let n = it.next().ok_or("--frames needs a count")?;
+                    opts.frames = Some(n.parse().map_err(|_| format!("bad count {}", n))?);
+                }
+                "--cycles" => {
+                    let n = it.next().ok_or("--cycles needs a count")?;
+                    opts.cycles = Some(n.parse().map_err(|_| format!("bad count {}", n))?);
+                }
+                "--until" => {
+                    let c = it.next().ok_or("--until needs a condition")?;
+                    opts.until = Some(c.to_string());
+                }
+                "--png" => {
+                    let f = it.next().ok_or("--png needs a file")?;
+                    opts.png = Some(f.to_string());
+                }
+                "--dump" => {
+                    let f = it.next().ok_or("--dump needs a file")?;
+                    opts.dump = Some(f.to_string());
+                }
+                "--expect-hash" => {
+                    let h = it.next().ok_or("--expect-hash needs a hash")?;
+                    opts.expect_hash = Some(h.to_string());
+                }
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                s if s.starts_with("--") => return Err(format!("unknown option {}\n{}", s, USAGE)),
+                s => opts.rom = s.to_string(),
+            }
+        }
+        return Ok(opts);
+    }
+}
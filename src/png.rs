@@ -0,0 +1,97 @@
+// Just enough PNG to save frames: 8 bit RGB, uncompressed deflate, tEXt chunks
+use crate::consts::*;
+use crate::gpu;
+
+// Also the rom checksum in .cdl files
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for d in data {
+        a = (a + *d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return b << 16 | a;
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let st = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[st..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream of stored blocks
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut z = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        z.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(b) = blocks.next() {
+        z.push(blocks.peek().is_none() as u8);
+        z.extend_from_slice(&(b.len() as u16).to_le_bytes());
+        z.extend_from_slice(&(!(b.len() as u16)).to_le_bytes());
+        z.extend_from_slice(b);
+    }
+    z.extend_from_slice(&adler32(data).to_be_bytes());
+    return z;
+}
+
+// rgb is w*h pixels of 3 bytes, text is keyword, value pairs
+pub fn encode(w: usize, h: usize, rgb: &[u8], text: &[(&str, String)]) -> Vec<u8> {
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(w as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(h as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit, RGB, deflate, no filter, no interlace
+    chunk(&mut out, b"IHDR", &ihdr);
+
+    for (k, v) in text {
+        let mut t = k.as_bytes().to_vec();
+        t.push(0);
+        t.extend(v.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })); // Latin-1
+        chunk(&mut out, b"tEXt", &t);
+    }
+
+    // Each row starts with filter type 0
+    let mut raw = Vec::with_capacity((w*3 + 1) * h);
+    for row in rgb.chunks(w*3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    chunk(&mut out, b"IDAT", &zlib(&raw));
+    chunk(&mut out, b"IEND", &[]);
+    return out;
+}
+
+// A frame as RGB, each pixel scale x scale
+pub fn frame_rgb(frame: &[u16], scale: usize) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(frame.len() * 3 * scale * scale);
+    for row in frame.chunks(WIDTH) {
+        let st = rgb.len();
+        for pix in row {
+            let (r, g, b) = gpu::rgb(*pix);
+            for _ in 0..scale {
+                rgb.extend_from_slice(&[r, g, b]);
+            }
+        }
+        let end = rgb.len();
+        for _ in 1..scale {
+            rgb.extend_from_within(st..end);
+        }
+    }
+    return rgb;
+}
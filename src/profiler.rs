@@ -0,0 +1,155 @@
+// Cycle profiler
+// Cycles are counted per bank:pc, and per call stack in a tree of frames that
+// follows the cpu's shadow call stack. Functions are named by their label when
+// there is one, bank:addr otherwise.
+use crate::cpu::{Cause, Cpu, Frame};
+use crate::disasm;
+use crate::mem::Mem;
+use crate::symbols::Symbols;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+type Func = (u16, u16); // bank, entry address
+
+// One node per distinct call path
+struct Node {
+    func: Func,
+    parent: usize,
+    kids: HashMap<Func, usize>,
+    cycles: u64, // exclusive
+}
+
+pub struct Profiler {
+    flat: HashMap<(u16, u16), u64>,
+    nodes: Vec<Node>,
+    stack: Vec<(usize, u16, u16)>, // node, and the sp and target of the cpu frame it follows
+    pre: (u16, u16, u64), // bank, pc and clk before the current instruction
+    pub total: u64,
+}
+
+fn loc(f: Func) -> String {
+    return format!("{:02X}:{:04X}", f.0, f.1);
+}
+
+fn name(f: Func, syms: &Symbols) -> String {
+    return syms.name(f.0, f.1).map_or(loc(f), |s| s.to_string());
+}
+
+impl Profiler {
+    // Root frame is the function at the current pc
+    pub fn new(cpu: &Cpu, mem: &Mem) -> Profiler {
+        let root = (mem.bank_of(cpu.pc), cpu.pc);
+        return Profiler {
+            flat: HashMap::new(),
+            nodes: vec![Node { func: root, parent: 0, kids: HashMap::new(), cycles: 0 }],
+            stack: vec![(0, 0, 0)],
+            pre: (0, 0, 0),
+            total: 0,
+        };
+    }
+
+    pub fn begin(&mut self, cpu: &Cpu, mem: &Mem) {
+        self.pre = (mem.bank_of(cpu.pc), cpu.pc, cpu.clk);
+    }
+
+    // Match our stack to the cpu's, stack[i + 1] follows calls[i]
+    fn sync(&mut self, calls: &[Frame]) {
+        let same = |s: &(usize, u16, u16), f: &Frame| s.1 == f.sp && s.2 == f.target;
+        let mut keep = 1;
+        while keep < self.stack.len() && keep <= calls.len() && same(&self.stack[keep], &calls[keep - 1]) {
+            keep += 1;
+        }
+        self.stack.truncate(keep);
+
+        for f in calls[keep - 1..].iter() {
+            let func = (f.bank, f.target);
+            let top = self.stack.last().unwrap().0;
+            let n = self.nodes.len();
+            let node = *self.nodes[top].kids.entry(func).or_insert(n);
+            if node == n {
+                self.nodes.push(Node { func, parent: top, kids: HashMap::new(), cycles: 0 });
+            }
+            self.stack.push((node, f.sp, f.target));
+        }
+    }
+
+    pub fn end(&mut self, cpu: &Cpu) {
+        let (bank, pc, clk) = self.pre;
+        let cycles = cpu.clk - clk;
+        self.total += cycles;
+
+        // Interrupt dispatch runs no instruction, charge it to the handler
+        let int = cpu.calls.last().is_some_and(|f| matches!(f.cause, Cause::Int(_)) && f.sp == cpu.sp && f.ret == pc);
+        if int {
+            self.sync(&cpu.calls);
+        } else {
+            *self.flat.entry((bank, pc)).or_insert(0) += cycles;
+        }
+        let top = self.stack.last().unwrap().0;
+        self.nodes[top].cycles += cycles;
+        self.sync(&cpu.calls);
+    }
+
+    fn path(&self, mut n: usize) -> Vec<Func> {
+        let mut p = vec![self.nodes[n].func];
+        while n != 0 {
+            n = self.nodes[n].parent;
+            p.push(self.nodes[n].func);
+        }
+        p.reverse();
+        return p;
+    }
+
+    // Flamegraph collapsed stacks, one "a;b;c cycles" line per path
+    pub fn write_collapsed(&self, out: &mut impl Write, syms: &Symbols) -> io::Result<()> {
+        for (i, n) in self.nodes.iter().enumerate() {
+            if n.cycles == 0 {
+                continue;
+            }
+            let p: Vec<String> = self.path(i).into_iter().map(|f| name(f, syms)).collect();
+            writeln!(out, "{} {}", p.join(";"), n.cycles)?;
+        }
+        return Ok(());
+    }
+
+    // (func, inclusive, exclusive), recursion is only counted once
+    fn functions(&self) -> Vec<(Func, u64, u64)> {
+        // Kids always come after their parent
+        let mut tot: Vec<u64> = self.nodes.iter().map(|n| n.cycles).collect();
+        for i in (1..self.nodes.len()).rev() {
+            tot[self.nodes[i].parent] += tot[i];
+        }
+
+        let mut funcs: HashMap<Func, (u64, u64)> = HashMap::new();
+        for (i, n) in self.nodes.iter().enumerate() {
+            let e = funcs.entry(n.func).or_insert((0, 0));
+            e.1 += n.cycles;
+            if !self.path(i).iter().rev().skip(1).any(|f| *f == n.func) {
+                e.0 += tot[i];
+            }
+        }
+        return funcs.into_iter().map(|(f, (i, e))| (f, i, e)).collect();
+    }
+
+    pub fn report(&self, mem: &Mem, syms: &Symbols, top: usize) {
+        let pct = |c: u64| c as f64 * 100. / self.total.max(1) as f64;
+        println!("profile: {} cycles", self.total);
+
+        let mut flat: Vec<_> = self.flat.iter().collect();
+        flat.sort_by_key(|f| Reverse(*f.1));
+        println!("\n{:>12} {:>6}  hot spot", "cycles", "%");
+        for ((bank, pc), c) in flat.into_iter().take(top) {
+            let ins = disasm::decode(|a| mem.read(a), *pc);
+            let at = syms.near(*bank, *pc).map_or("".to_string(), |s| format!("  ; {}", s));
+            println!("{:>12} {:>6.2}  {}  {}{}", c, pct(*c), loc((*bank, *pc)), ins.text_with(&|a| syms.label(mem, a)), at);
+        }
+
+        let mut funcs = self.functions();
+        funcs.sort_by_key(|f| Reverse(f.1));
+        println!("\n{:>12} {:>6} {:>12} {:>6}  function", "inclusive", "%", "exclusive", "%");
+        for (f, i, e) in funcs.into_iter().take(top) {
+            println!("{:>12} {:>6.2} {:>12} {:>6.2}  {}", i, pct(i), e, pct(e), name(f, syms));
+        }
+    }
+}
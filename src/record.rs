@@ -0,0 +1,165 @@
+// Video recording, one frame per 70224 cycles so the rate is exactly 4194304/70224 Hz
+// (59.7275). Either Y4M with a WAV next to it, or raw RGB24 frames piped into a
+// command, like: ffmpeg -f rawvideo -pix_fmt rgb24 -s 160x144 -r 4194304/70224 -i - out.mp4
+use crate::consts::*;
+use crate::gpu::rgb;
+use crate::frontend::SAMPLE_RATE;
+use crate::png;
+use crate::screenshot::{file_title, numbered};
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+enum Sink {
+    Y4m {
+        video: BufWriter<File>,
+        audio: BufWriter<File>,
+        samples: u32, // stereo samples written to the wav so far
+        path: PathBuf,
+    },
+    Pipe(Child),
+}
+
+pub struct Recorder {
+    path: Option<PathBuf>, // --record, numbered after the first, else numbered files in dir
+    cmd: Option<String>, // --record-cmd
+    dir: PathBuf,
+    next: u32,
+    sink: Option<Sink>,
+}
+
+// BT.601 studio range YCbCr of a pixel, for Y4M
+fn yuv(pix: u16) -> [u8; 3] {
+    let (r, g, b) = rgb(pix);
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    let y = 16. + (65.738*r + 129.057*g + 25.064*b) / 256.;
+    let cb = 128. + (-37.945*r - 74.494*g + 112.439*b) / 256.;
+    let cr = 128. + (112.439*r - 94.154*g - 18.285*b) / 256.;
+    return [y.round() as u8, cb.round() as u8, cr.round() as u8];
+}
+
+fn wav_header(samples: u32) -> Vec<u8> {
+    let data = samples * 4;
+    let mut h = Vec::with_capacity(44);
+    h.extend_from_slice(b"RIFF");
+    h.extend_from_slice(&(36 + data).to_le_bytes());
+    h.extend_from_slice(b"WAVEfmt ");
+    h.extend_from_slice(&16u32.to_le_bytes());
+    h.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    h.extend_from_slice(&2u16.to_le_bytes()); // stereo
+    h.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    h.extend_from_slice(&(SAMPLE_RATE * 4).to_le_bytes());
+    h.extend_from_slice(&4u16.to_le_bytes());
+    h.extend_from_slice(&16u16.to_le_bytes());
+    h.extend_from_slice(b"data");
+    h.extend_from_slice(&data.to_le_bytes());
+    return h;
+}
+
+impl Recorder {
+    pub fn new(path: Option<&str>, cmd: Option<&str>, dir: &str) -> Recorder {
+        return Recorder {
+            path: path.map(PathBuf::from),
+            cmd: cmd.map(|c| c.to_string()),
+            dir: PathBuf::from(dir),
+            next: 1,
+            sink: None,
+        };
+    }
+
+    pub fn active(&self) -> bool {
+        return self.sink.is_some();
+    }
+
+    // Returns what it's recording to
+    pub fn start(&mut self, title: &str) -> Result<String, String> {
+        if let Some(cmd) = &self.cmd {
+            let child = Command::new("sh").arg("-c").arg(cmd).stdin(Stdio::piped()).spawn()
+                .map_err(|e| format!("{}: {}", cmd, e))?;
+            self.sink = Some(Sink::Pipe(child));
+            return Ok(cmd.clone());
+        }
+
+        let path = match &self.path {
+            Some(p) if self.next == 1 => {
+                self.next += 1;
+                p.clone()
+            }
+            Some(p) => {
+                let stem = p.file_stem().map_or("video".into(), |s| s.to_string_lossy());
+                let ext = p.extension().map_or("y4m".into(), |s| s.to_string_lossy());
+                numbered(p.parent().unwrap_or(Path::new("")), &stem, &ext, &mut self.next)
+            }
+            None => {
+                fs::create_dir_all(&self.dir).map_err(|e| format!("{}: {}", self.dir.display(), e))?;
+                numbered(&self.dir, &file_title(title, "video"), "y4m", &mut self.next)
+            }
+        };
+        let err = |p: &PathBuf, e: std::io::Error| format!("{}: {}", p.display(), e);
+        let wav = path.with_extension("wav");
+        let mut video = BufWriter::new(File::create(&path).map_err(|e| err(&path, e))?);
+        let mut audio = BufWriter::new(File::create(&wav).map_err(|e| err(&wav, e))?);
+        writeln!(video, "YUV4MPEG2 W{} H{} F4194304:70224 Ip A1:1 C444", WIDTH, HEIGHT).map_err(|e| err(&path, e))?;
+        audio.write_all(&wav_header(0)).map_err(|e| err(&wav, e))?;
+        self.sink = Some(Sink::Y4m { video, audio, samples: 0, path: path.clone() });
+        return Ok(format!("{} and {}", path.display(), wav.display()));
+    }
+
+    // Finish the files, or wait for the command
+    pub fn stop(&mut self) -> Result<(), String> {
+        match self.sink.take() {
+            Some(Sink::Y4m { video, audio, samples, path }) => {
+                let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+                video.into_inner().map_err(|e| err(e.into_error()))?;
+                let mut f = audio.into_inner().map_err(|e| err(e.into_error()))?;
+                f.seek(SeekFrom::Start(0)).map_err(err)?;
+                f.write_all(&wav_header(samples)).map_err(err)?;
+            }
+            Some(Sink::Pipe(mut child)) => {
+                drop(child.stdin.take());
+                child.wait().map_err(|e| e.to_string())?;
+            }
+            None => {}
+        }
+        return Ok(());
+    }
+
+    pub fn frame(&mut self, frame: &[u16]) -> Result<(), String> {
+        let res = match &mut self.sink {
+            Some(Sink::Y4m { video, .. }) => {
+                let pix: Vec<[u8; 3]> = frame.iter().map(|s| yuv(*s)).collect();
+                let mut dat = b"FRAME\n".to_vec();
+                for plane in 0..3 {
+                    dat.extend(pix.iter().map(|p| p[plane]));
+                }
+                video.write_all(&dat)
+            }
+            Some(Sink::Pipe(child)) => child.stdin.as_mut().unwrap().write_all(&png::frame_rgb(frame, 1)),
+            None => Ok(())
+        };
+        return self.check(res);
+    }
+
+    // Interleaved stereo, the pipe doesn't get sound
+    pub fn audio(&mut self, samples: &[i16]) -> Result<(), String> {
+        let res = match &mut self.sink {
+            Some(Sink::Y4m { audio, samples: n, .. }) => {
+                *n += samples.len() as u32 / 2;
+                let dat: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+                audio.write_all(&dat)
+            }
+            _ => Ok(())
+        };
+        return self.check(res);
+    }
+
+    // A write failed, stop recording
+    fn check(&mut self, res: std::io::Result<()>) -> Result<(), String> {
+        if let Err(e) = res {
+            let _ = self.stop();
+            return Err(format!("recording stopped: {}", e));
+        }
+        return Ok(());
+    }
+}
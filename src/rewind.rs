@@ -0,0 +1,170 @@
+// Reverse execution
+// Snapshots are taken every SNAP_EVERY steps, going back restores the closest
+// one and replays forward. Key presses are logged so replays come out the same.
+use crate::cpu::Cpu;
+use crate::gb::Gb;
+use crate::gpu::GpuState;
+use crate::input::{Input, KeyCode};
+use crate::mem::Mem;
+use crate::timer::Timer;
+use std::collections::VecDeque;
+
+const SNAP_EVERY: u64 = 8192;
+
+struct Snap {
+    step: u64,
+    cpu: Cpu,
+    mem: Mem,
+    gpu: GpuState,
+    timer: Timer,
+    input: Input,
+}
+
+pub struct History {
+    pub steps: u64, // steps run so far
+    snaps: VecDeque<Snap>,
+    keys: VecDeque<(u64, KeyCode, bool)>,
+    max_snaps: usize,
+}
+
+impl History {
+    pub fn new(budget_mb: usize) -> History {
+        History {
+            steps: 0,
+            snaps: VecDeque::new(),
+            keys: VecDeque::new(),
+            max_snaps: budget_mb * 1024 * 1024 / std::mem::size_of::<Snap>(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        return self.max_snaps != 0;
+    }
+
+    // Oldest step we can go back to
+    pub fn start(&self) -> u64 {
+        return self.snaps.front().map_or(self.steps, |s| s.step);
+    }
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(32)
+    }
+}
+
+fn restore(gb: &mut Gb, i: usize) {
+    let s = &gb.hist.snaps[i];
+    // Watches and rom patches are the debugger's, not machine state
+    let watches = std::mem::take(&mut gb.mem.watches);
+    let (rom, rom_bank) = (gb.mem.rom.clone(), gb.mem.rom_bank.clone());
+    gb.cpu = s.cpu.clone();
+    gb.mem = s.mem.clone();
+    gb.mem.watches = watches;
+    gb.mem.rom = rom;
+    gb.mem.rom_bank = rom_bank;
+    gb.gpu.load(&s.gpu);
+    gb.timer = s.timer.clone();
+    gb.input = s.input.clone();
+    gb.hist.steps = s.step;
+}
+
+// Called before every step
+pub fn record(gb: &mut Gb) {
+    let h = &mut gb.hist;
+    for (key, kp) in gb.input.log.drain(..) {
+        if h.enabled() {
+            h.keys.push_back((h.steps, key, kp));
+        }
+    }
+    if !h.enabled() || !h.steps.is_multiple_of(SNAP_EVERY) {
+        return;
+    }
+
+    if h.snaps.len() >= h.max_snaps {
+        h.snaps.pop_front();
+        let start = h.start();
+        while h.keys.front().is_some_and(|k| k.0 < start) {
+            h.keys.pop_front();
+        }
+    }
+    h.snaps.push_back(Snap {
+        step: h.steps,
+        cpu: gb.cpu.clone(),
+        mem: gb.mem.clone(),
+        gpu: gb.gpu.save(),
+        timer: gb.timer.clone(),
+        input: gb.input.clone(),
+    });
+}
+
+// Run forward from the current snapshot position to step `to`
+fn replay(gb: &mut Gb, to: u64, mut each: impl FnMut(&Gb)) {
+    let mut k = gb.hist.keys.iter().position(|k| k.0 > gb.hist.steps).unwrap_or(gb.hist.keys.len());
+    while gb.hist.steps < to {
+        each(gb);
+        gb.exec();
+        gb.hist.steps += 1;
+        while k < gb.hist.keys.len() && gb.hist.keys[k].0 == gb.hist.steps {
+            let (_, key, kp) = gb.hist.keys[k];
+            gb.input.key(&mut gb.mem, key, kp);
+            k += 1;
+        }
+    }
+    gb.input.log.clear();
+    gb.mem.watch_hit.set(None);
+    gb.cpu.bad_ret = None;
+}
+
+// Latest snapshot at or before step
+fn snap_before(gb: &Gb, step: u64) -> Option<usize> {
+    return gb.hist.snaps.iter().rposition(|s| s.step <= step);
+}
+
+// Drop everything after the current step, we are taking a new path from here
+fn truncate(gb: &mut Gb) {
+    let h = &mut gb.hist;
+    while h.snaps.back().is_some_and(|s| s.step > h.steps) {
+        h.snaps.pop_back();
+    }
+    while h.keys.back().is_some_and(|k| k.0 > h.steps) {
+        h.keys.pop_back();
+    }
+}
+
+// Go back to step, false if it's older than the history we have
+pub fn seek(gb: &mut Gb, step: u64) -> bool {
+    if step > gb.hist.steps {
+        return false;
+    }
+    let i = match snap_before(gb, step) {
+        Some(i) => i,
+        None => return false
+    };
+    restore(gb, i);
+    replay(gb, step, |_| {});
+    truncate(gb);
+    return true;
+}
+
+// Go back to the last step before now where hit is true,
+// or to the start of history if there is none
+pub fn reverse_until(gb: &mut Gb, hit: &dyn Fn(&Gb) -> bool) -> bool {
+    let mut end = gb.hist.steps;
+    while let Some(i) = snap_before(gb, end.saturating_sub(1)) {
+        let start = gb.hist.snaps[i].step;
+        if start >= end {
+            break;
+        }
+        restore(gb, i);
+        let mut found = None;
+        replay(gb, end, |g| if hit(g) { found = Some(g.hist.steps) });
+        if let Some(step) = found {
+            seek(gb, step);
+            return true;
+        }
+        end = start;
+    }
+    seek(gb, end);
+    return false;
+}
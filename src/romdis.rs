@@ -0,0 +1,338 @@
+// Static whole-rom disassembler
+// Walks code from the entry points, following branches, and writes RGBDS
+// source that builds back into the same rom. Whatever isn't reached is data.
+// Bank switches are only followed for the usual "ld a,n / ld [$2000-$3FFF],a".
+use crate::cdl;
+use crate::disasm::{self, Instr};
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+const ENTRIES: [u16; 14] = [
+    0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, // RST
+    0x40, 0x48, 0x50, 0x58, 0x60, // interrupts
+    0x100,
+];
+
+pub struct Dis {
+    rom: Vec<u8>,
+    cdl: Option<Vec<u8>>,
+    syms: Symbols,
+    start: Vec<bool>, // an instruction starts here
+    code: Vec<bool>, // part of an instruction
+    refs: BTreeMap<usize, &'static str>, // branch targets, and the label prefix for them
+    xref: HashMap<usize, usize>, // instruction to the rom offset it branches to
+    queue: Vec<(u16, u16)>,
+}
+
+impl Dis {
+    pub fn new(rom: Vec<u8>, cdl: Option<Vec<u8>>, syms: Symbols) -> Dis {
+        let n = rom.len();
+        return Dis {
+            rom,
+            cdl,
+            syms,
+            start: vec![false; n],
+            code: vec![false; n],
+            refs: BTreeMap::new(),
+            xref: HashMap::new(),
+            queue: Vec::new(),
+        };
+    }
+
+    // A short last bank counts, its tail is written as data
+    fn banks(&self) -> u16 {
+        return self.rom.len().div_ceil(0x4000) as u16;
+    }
+
+    // Rom offset of addr with bank mapped at 0x4000-0x7FFF
+    fn offset(&self, bank: u16, addr: u16) -> Option<usize> {
+        let off = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF if bank != 0 => bank as usize * 0x4000 + (addr as usize - 0x4000),
+            _ => return None
+        };
+        return if off < self.rom.len() { Some(off) } else { None };
+    }
+
+    fn data_only(&self, off: usize) -> bool {
+        return self.cdl.as_ref().is_some_and(|c| c[off] & cdl::CODE == 0 && c[off] != 0);
+    }
+
+    fn decode(&self, bank: u16, addr: u16) -> Instr {
+        return disasm::decode(|a| self.offset(bank, a).map_or(0, |o| self.rom[o]), addr);
+    }
+
+    // Follow one run of code, queueing branch targets
+    fn walk(&mut self, bank: u16, mut pc: u16) {
+        let mut mapped = if bank != 0 { Some(bank) } else if self.banks() == 2 { Some(1) } else { None };
+        let mut a = None;
+        loop {
+            let off = match self.offset(bank, pc) {
+                Some(o) => o,
+                None => return
+            };
+            if self.code[off] || self.data_only(off) {
+                return;
+            }
+            let ins = self.decode(bank, pc);
+            let end = off + ins.len as usize;
+            if !ins.defined() || end > (off / 0x4000 + 1) * 0x4000 || end > self.rom.len()
+                || self.code[off..end].iter().any(|c| *c) {
+                return;
+            }
+            self.start[off] = true;
+            for c in self.code[off..end].iter_mut() {
+                *c = true;
+            }
+
+            // Track the rom bank for branches into 0x4000-0x7FFF
+            let tmpl = ins.template();
+            if !ins.cb && ins.op == 0x3E {
+                a = Some(ins.arg);
+            } else if !ins.cb && ins.op == 0xEA && (0x2000..0x4000).contains(&ins.arg) {
+                mapped = a.map(|b| b.max(1)).or(mapped);
+            } else if !(tmpl.starts_with("LD [") || tmpl.starts_with("LDH [")) || !tmpl.ends_with(",A") {
+                a = None;
+            }
+
+            if let Some(t) = ins.target() {
+                let tb = if t < 0x4000 { Some(0) } else { mapped };
+                if let Some(toff) = tb.and_then(|b| self.offset(b, t)) {
+                    self.xref.insert(off, toff);
+                    let kind = if ins.is_call() { "Call" } else { "Jump" };
+                    let e = self.refs.entry(toff).or_insert(kind);
+                    if kind == "Call" {
+                        *e = kind;
+                    }
+                    self.queue.push((tb.unwrap(), t));
+                }
+            }
+            if ins.is_end() {
+                return;
+            }
+            pc = pc.wrapping_add(ins.len);
+        }
+    }
+
+    // Popped last in first, so the entry points and symbols are walked before the log's
+    // code bytes. Mesen marks operands CODE too, they're skipped once walked over
+    pub fn run(&mut self) {
+        if let Some(c) = &self.cdl {
+            for (off, f) in c.iter().enumerate() {
+                if f & (cdl::CODE | cdl::OPERAND) == cdl::CODE {
+                    let addr = if off < 0x4000 { off } else { 0x4000 + off % 0x4000 };
+                    self.queue.push(((off / 0x4000) as u16, addr as u16));
+                }
+            }
+        }
+        for (bank, addr, _) in self.syms.iter() {
+            if addr < 0x8000 {
+                self.queue.push((bank, addr));
+            }
+        }
+        for e in ENTRIES.iter() {
+            self.queue.push((0, *e));
+        }
+        while let Some((bank, addr)) = self.queue.pop() {
+            self.walk(bank, addr);
+        }
+    }
+
+    // Labels that can be placed, at instruction starts or in data
+    fn labels(&self) -> HashMap<usize, String> {
+        let mut labels = HashMap::new();
+        for (bank, addr, name) in self.syms.iter() {
+            if let Some(off) = self.offset(bank, addr) {
+                labels.insert(off, name.replace('.', "_"));
+            }
+        }
+        for (off, kind) in self.refs.iter() {
+            let bank = off / 0x4000;
+            let addr = if bank == 0 { *off } else { 0x4000 + off % 0x4000 };
+            labels.entry(*off).or_insert(format!("{}_{:03X}_{:04X}", kind, bank, addr));
+        }
+        labels.retain(|off, _| self.start[*off] || !self.code[*off]);
+        return labels;
+    }
+
+    fn bank_source(&self, bank: u16, labels: &HashMap<usize, String>) -> String {
+        let mut s = String::new();
+        if bank == 0 {
+            writeln!(s, "SECTION \"ROM Bank $000\", ROM0[$0000]").unwrap();
+        } else {
+            writeln!(s, "SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]", bank, bank).unwrap();
+        }
+
+        let base = bank as usize * 0x4000;
+        let top = (base + 0x4000).min(self.rom.len());
+        let org = if bank == 0 { 0 } else { 0x4000 };
+        let mut off = base;
+        while off < top {
+            if let Some(l) = labels.get(&off) {
+                writeln!(s, "\n{}:", l).unwrap();
+            }
+            let addr = (off - base + org) as u16;
+
+            if self.start[off] {
+                let ins = self.decode(bank, addr);
+                let text = if !ins.cb && ins.op == 0x10 && ins.arg != 0 {
+                    format!("db $10, ${:02X}", ins.arg) // rgbasm always writes STOP as 10 00
+                } else {
+                    let to = self.xref.get(&off);
+                    ins.text_with(&|a| {
+                        if let Some(t) = to.filter(|_| Some(a) == ins.target()) {
+                            return labels.get(t).cloned();
+                        }
+                        match a {
+                            0x0000..=0x3FFF => labels.get(&(a as usize)).cloned(),
+                            0x4000..=0x7FFF if bank != 0 => labels.get(&(base + a as usize - 0x4000)).cloned(),
+                            0x8000..=0xFFFF => self.syms.name(0, a).or(self.syms.name(1, a)).map(|n| n.replace('.', "_")),
+                            _ => None
+                        }
+                    })
+                };
+                writeln!(s, "    {}", text).unwrap();
+                off += ins.len as usize;
+                continue;
+            }
+
+            // Data up to the next label or instruction
+            let mut end = off + 1;
+            while end < top && !self.start[end] && !labels.contains_key(&end) {
+                end += 1;
+            }
+            write_data(&mut s, &self.rom[off..end]);
+            off = end;
+        }
+        return s;
+    }
+
+    pub fn write(&self, dir: &Path, name: &str) -> Result<(), String> {
+        let err = |e: std::io::Error| format!("{}: {}", dir.display(), e);
+        fs::create_dir_all(dir).map_err(err)?;
+        let labels = self.labels();
+
+        let mut main = String::new();
+        writeln!(main, "; Disassembly of {}", name).unwrap();
+        writeln!(main, "; rgbasm -o game.o game.asm && rgblink -o game.gb game.o\n").unwrap();
+        for (_, addr, n) in self.syms.iter().filter(|s| s.1 >= 0x8000) {
+            writeln!(main, "DEF {} EQU ${:04X}", n.replace('.', "_"), addr).unwrap();
+        }
+        writeln!(main).unwrap();
+        for bank in 0..self.banks() {
+            let file = format!("bank_{:03X}.asm", bank);
+            fs::write(dir.join(&file), self.bank_source(bank, &labels)).map_err(err)?;
+            writeln!(main, "INCLUDE \"{}\"", file).unwrap();
+        }
+        return fs::write(dir.join("game.asm"), main).map_err(err);
+    }
+}
+
+// db lines, with ds for long runs of one byte
+fn write_data(s: &mut String, dat: &[u8]) {
+    let mut i = 0;
+    while i < dat.len() {
+        let run = dat[i..].iter().take_while(|b| **b == dat[i]).count();
+        if run >= 16 {
+            writeln!(s, "    ds {}, ${:02X}", run, dat[i]).unwrap();
+            i += run;
+            continue;
+        }
+        let mut n = 1;
+        while i + n < dat.len() && n < 16 && dat[i + n..].iter().take_while(|b| **b == dat[i + n]).count() < 16 {
+            n += 1;
+        }
+        let bytes: Vec<String> = dat[i..i + n].iter().map(|b| format!("${:02X}", b)).collect();
+        writeln!(s, "    db {}", bytes.join(", ")).unwrap();
+        i += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    // Assemble the bank sources back, the way rgbasm would lay them out
+    fn build(dis: &Dis) -> Vec<u8> {
+        let labels = dis.labels();
+        let srcs: Vec<String> = (0..dis.banks()).map(|b| dis.bank_source(b, &labels)).collect();
+        let mut addrs: HashMap<String, u32> = HashMap::new();
+        let mut out = Vec::new();
+        // The first pass only finds the labels, instruction sizes don't depend on them
+        for pass in 0..2 {
+            out.clear();
+            for (bank, src) in srcs.iter().enumerate() {
+                let org = if bank == 0 { 0 } else { 0x4000 };
+                let mut bytes = Vec::new();
+                for line in src.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with("SECTION")) {
+                    let addr = (org + bytes.len()) as u16;
+                    if let Some(l) = line.strip_suffix(':') {
+                        addrs.insert(l.to_string(), addr as u32);
+                    } else if let Some(d) = line.strip_prefix("db ") {
+                        bytes.extend(d.split(", ").map(|b| u8::from_str_radix(&b[1..], 16).unwrap()));
+                    } else if let Some(d) = line.strip_prefix("ds ") {
+                        let (n, b) = d.split_once(", $").unwrap();
+                        bytes.extend(std::iter::repeat_n(u8::from_str_radix(b, 16).unwrap(), n.parse().unwrap()));
+                    } else {
+                        let eval = |e: &str| match (e.strip_prefix('$'), addrs.get(e)) {
+                            (Some(h), _) => u32::from_str_radix(h, 16).map_err(|e| e.to_string()),
+                            (None, Some(a)) => Ok(*a),
+                            (None, None) if pass == 0 => Ok(addr as u32),
+                            _ => Err(format!("no label {}", e))
+                        };
+                        bytes.extend(asm::assemble(line, addr, &eval).unwrap());
+                    }
+                }
+                assert!(bytes.len() <= 0x4000, "bank {} is {} bytes", bank, bytes.len());
+                out.extend(bytes);
+            }
+        }
+        return out;
+    }
+
+    #[test]
+    fn covers_every_byte() {
+        let mut rom: Vec<u8> = (0..0x5123).map(|i| (i * 37 % 251) as u8).collect();
+        let code: [(usize, &[u8]); 3] = [
+            (0x0100, &[0x00, 0xC3, 0x50, 0x01]), // nop, jp $0150
+            (0x0150, &[0x3E, 0x01, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x18, 0xFE]), // ld a,1 / ld [$2000],a / call $4000 / jr @
+            (0x4000, &[0x21, 0x00, 0xC0, 0x2A, 0x20, 0xFD, 0xC9]), // ld hl,$C000 / ld a,[hl+] / jr nz / ret
+        ];
+        for (at, b) in code.iter() {
+            rom[*at..*at + b.len()].copy_from_slice(b);
+        }
+        for b in rom[0x4800..0x4830].iter_mut() {
+            *b = 0xAA; // a ds run
+        }
+
+        let mut dis = Dis::new(rom.clone(), None, Symbols::default());
+        dis.run();
+        assert_eq!(dis.banks(), 2);
+        assert!(dis.start[0x150] && dis.start[0x4000] && dis.start[0x4006]);
+        assert_eq!(dis.refs.get(&0x4000), Some(&"Call"));
+        assert_eq!(build(&dis), rom);
+    }
+
+    #[test]
+    fn mesen_cdl() {
+        let mut rom = vec![0; 0x8000];
+        let code = [0x00, 0xC3, 0x50, 0x01]; // nop, jp $0150
+        rom[0x100..0x104].copy_from_slice(&code);
+        let code = [0x3E, 0x01, 0x01, 0x34, 0x12, 0x18, 0xFE]; // ld a,1 / ld bc,$1234 / jr @
+        rom[0x150..0x157].copy_from_slice(&code);
+        // Mesen marks every byte of an instruction as code
+        let mut log = vec![0; 0x8000];
+        log[0x100..0x104].fill(cdl::CODE);
+        log[0x150..0x157].fill(cdl::CODE);
+
+        let mut dis = Dis::new(rom.clone(), Some(log), Symbols::default());
+        dis.run();
+        let starts: Vec<usize> = (0x100..0x157).filter(|o| dis.start[*o]).collect();
+        assert_eq!(starts, vec![0x100, 0x101, 0x150, 0x152, 0x155]);
+        assert_eq!(build(&dis), rom);
+    }
+}
@@ -0,0 +1,62 @@
+// Frames saved as PNG, named <title>_NNNN.png in a directory
+use crate::consts::*;
+use crate::gb::Gb;
+use crate::png;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// PNG of the last finished frame, with the rom title and frame number in tEXt chunks
+pub fn encode(gb: &Gb, scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let text = [
+        ("Title", gb.mem.rom_title()),
+        ("Frame", gb.gpu.frame_no.to_string()),
+        ("Software", "gameboy-emu".to_string()),
+    ];
+    return png::encode(WIDTH*scale, HEIGHT*scale, &png::frame_rgb(&gb.gpu.frame, scale), &text);
+}
+
+// The rom title made safe for a file name, or if it's empty
+pub fn file_title(title: &str, or: &str) -> String {
+    return match title.replace(|c: char| !c.is_ascii_alphanumeric(), "_") {
+        t if t.is_empty() => or.to_string(),
+        t => t
+    };
+}
+
+// dir/prefix_NNNN.ext with the first unused NNNN from next, next moves past it
+pub fn numbered(dir: &Path, prefix: &str, ext: &str, next: &mut u32) -> PathBuf {
+    let mut path = dir.join(format!("{}_{:04}.{}", prefix, next, ext));
+    while path.exists() {
+        *next += 1;
+        path = dir.join(format!("{}_{:04}.{}", prefix, next, ext));
+    }
+    *next += 1;
+    return path;
+}
+
+pub struct Screenshots {
+    dir: PathBuf,
+    scale: usize,
+    next: u32, // first number that might be free
+}
+
+impl Screenshots {
+    pub fn new(dir: &str, scale: usize) -> Screenshots {
+        return Screenshots {
+            dir: PathBuf::from(dir),
+            scale,
+            next: 1,
+        };
+    }
+
+    // Save the last finished frame under the next unused name
+    pub fn save(&mut self, gb: &Gb) -> Result<PathBuf, String> {
+        let dir = self.dir.clone();
+        let err = |e: std::io::Error| format!("{}: {}", dir.display(), e);
+        fs::create_dir_all(&dir).map_err(err)?;
+        let path = numbered(&dir, &file_title(&gb.mem.rom_title(), "screenshot"), "png", &mut self.next);
+        fs::write(&path, encode(gb, self.scale)).map_err(err)?;
+        return Ok(path);
+    }
+}
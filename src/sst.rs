@@ -0,0 +1,246 @@
+// SingleStepTests/sm83 conformance runner
+// https://github.com/SingleStepTests/sm83
+use crate::consts::*;
+use crate::cpu::{self, Cpu};
+use crate::mem::Bus;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+
+// (addr, val, is_write)
+type Access = (u16, u8, bool);
+
+// Flat 64k bus that records every bus cycle with the cpu clock it happened at
+struct TestBus {
+    ram: Vec<u8>,
+    log: RefCell<Vec<(u64, Access)>>,
+}
+
+impl Bus for TestBus {
+    fn read(&self, address: u16) -> u8 {
+        return self.ram[address as usize];
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        self.ram[address as usize] = val;
+    }
+
+    fn read_at(&self, clk: u64, address: u16) -> u8 {
+        let val = self.read(address);
+        self.log.borrow_mut().push((clk, (address, val, false)));
+        return val;
+    }
+
+    fn write_at(&mut self, clk: u64, address: u16, val: u8) {
+        self.write(address, val);
+        self.log.borrow_mut().push((clk, (address, val, true)));
+    }
+}
+
+impl Default for TestBus {
+    fn default() -> TestBus {
+        TestBus {
+            ram: vec![0; 0x10000],
+            log: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+const REGS: [(&str, usize); 8] = [
+    ("a", A), ("b", B), ("c", C), ("d", D), ("e", E), ("f", F), ("h", H), ("l", L)
+];
+
+fn num(state: &Value, key: &str) -> Result<u64, String> {
+    return state[key].as_u64().ok_or(format!("missing field {}", key));
+}
+
+fn ram_entries(state: &Value) -> Result<Vec<(u16, u8)>, String> {
+    let ram = state["ram"].as_array().ok_or("missing field ram")?;
+    let mut ents = Vec::with_capacity(ram.len());
+    for ent in ram {
+        match (ent[0].as_u64(), ent[1].as_u64()) {
+            (Some(addr), Some(val)) => ents.push((addr as u16, val as u8)),
+            _ => return Err(format!("bad ram entry {}", ent))
+        }
+    }
+    return Ok(ents);
+}
+
+// The access in each M-cycle, None for idle ones
+fn bus_cycles(test: &Value) -> Result<Vec<Option<Access>>, String> {
+    let cycles = test["cycles"].as_array().ok_or("missing field cycles")?;
+    let mut acc = Vec::with_capacity(cycles.len());
+    for cyc in cycles {
+        let kind = cyc[2].as_str().unwrap_or("---");
+        acc.push(match (cyc[0].as_u64(), cyc[1].as_u64()) {
+            (Some(a), Some(v)) if kind.contains('w') => Some((a as u16, v as u8, true)),
+            (Some(a), Some(v)) if kind.contains('r') => Some((a as u16, v as u8, false)),
+            _ => None
+        });
+    }
+    return Ok(acc);
+}
+
+fn setup(test: &Value) -> Result<(Cpu, TestBus), String> {
+    let init = &test["initial"];
+    let mut gb_cpu = Cpu::default();
+    let mut gb_mem = TestBus::default();
+    for (name, r) in REGS.iter() {
+        gb_cpu.regs[*r] = num(init, name)? as u8;
+    }
+    gb_cpu.pc = num(init, "pc")? as u16;
+    gb_cpu.sp = num(init, "sp")? as u16;
+    gb_cpu.ime = num(init, "ime")? != 0;
+    for (addr, val) in ram_entries(init)? {
+        gb_mem.ram[addr as usize] = val;
+    }
+    if let Some(ie) = init["ie"].as_u64() {
+        gb_mem.ram[PINT_E as usize] = ie as u8;
+    }
+    return Ok((gb_cpu, gb_mem));
+}
+
+// Returns a list of mismatches, empty on pass
+fn run_test(test: &Value) -> Result<Vec<String>, String> {
+    let (mut gb_cpu, mut gb_mem) = setup(test)?;
+    let fin = &test["final"];
+    let mut errs = Vec::new();
+
+    cpu::cpu_cycle(&mut gb_cpu, &mut gb_mem);
+
+    for (name, r) in REGS.iter() {
+        let want = num(fin, name)? as u8;
+        if gb_cpu.regs[*r] != want {
+            errs.push(format!("{}: got {:#04X} want {:#04X}", name, gb_cpu.regs[*r], want));
+        }
+    }
+    let want = num(fin, "pc")? as u16;
+    if gb_cpu.pc != want {
+        errs.push(format!("pc: got {:#06X} want {:#06X}", gb_cpu.pc, want));
+    }
+    let want = num(fin, "sp")? as u16;
+    if gb_cpu.sp != want {
+        errs.push(format!("sp: got {:#06X} want {:#06X}", gb_cpu.sp, want));
+    }
+    let want = num(fin, "ime")? != 0;
+    if gb_cpu.ime != want {
+        errs.push(format!("ime: got {} want {}", gb_cpu.ime, want));
+    }
+    for (addr, want) in ram_entries(fin)? {
+        let got = gb_mem.ram[addr as usize];
+        if got != want {
+            errs.push(format!("[{:#06X}]: got {:#04X} want {:#04X}", addr, got, want));
+        }
+    }
+
+    // Each access has to land in the M-cycle the vector has it in
+    let want = bus_cycles(test)?;
+    let mut got = vec![None; want.len()];
+    for (clk, acc) in gb_mem.log.borrow().iter() {
+        let m = (*clk / 4) as usize;
+        if clk % 4 != 0 || m >= got.len() || got[m].is_some() {
+            errs.push(format!("bus: {:X?} at clock {} doesn't fit a free M-cycle", acc, clk));
+        } else {
+            got[m] = Some(*acc);
+        }
+    }
+    if got != want {
+        errs.push(format!("bus: got {:X?} want {:X?}", got, want));
+    }
+    if gb_cpu.clk != (want.len() * 4) as u64 {
+        errs.push(format!("cycles: got {} want {}", gb_cpu.clk, want.len() * 4));
+    }
+
+    return Ok(errs);
+}
+
+fn run_file(path: &Path, verbose: bool) -> Result<(usize, usize), String> {
+    let dat = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let tests: Value = serde_json::from_str(&dat).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let tests = tests.as_array().ok_or(format!("{}: expected a list of tests", path.display()))?;
+
+    let mut failed = 0;
+    for test in tests {
+        let errs = run_test(test)?;
+        if errs.is_empty() {
+            continue;
+        }
+        if verbose || failed == 0 {
+            println!("  {}: {}", test["name"].as_str().unwrap_or("?"), errs.join(", "));
+        }
+        failed += 1;
+    }
+    return Ok((tests.len(), failed));
+}
+
+// Run every *.json in dir, optionally only files starting with filter
+pub fn run(dir: &str, filter: Option<&str>, verbose: bool) -> Result<bool, String> {
+    let mut files: Vec<_> = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|x| x == "json"))
+        .filter(|p| {
+            let name = p.file_name().unwrap().to_string_lossy();
+            filter.is_none_or(|f| name.starts_with(f))
+        })
+        .collect();
+    files.sort();
+
+    let (mut total, mut failed, mut bad_files) = (0, 0, 0);
+    for f in files.iter() {
+        let (t, fl) = run_file(f, verbose)?;
+        total += t;
+        failed += fl;
+        if fl != 0 {
+            bad_files += 1;
+            println!("FAIL {} ({}/{})", f.file_name().unwrap().to_string_lossy(), fl, t);
+        }
+    }
+
+    println!("{} files, {} opcodes failing, {}/{} tests passed", files.len(), bad_files, total - failed, total);
+    return Ok(failed == 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CB op on [HL] at C000, which holds 80 and ends up as res with flags f
+    fn cb_hl(op: u8, res: u8, f: u8, write_cycle: usize) -> Value {
+        let mut cycles = vec![
+            serde_json::json!([0x100, 0xCB, "r-m"]),
+            serde_json::json!([0x101, op, "r-m"]),
+            serde_json::json!([0xC000, 0x80, "r-m"]),
+            serde_json::json!([null, null, "---"]),
+        ];
+        cycles.insert(write_cycle, serde_json::json!([0xC000, res, "-wm"]));
+        cycles.truncate(4);
+        return serde_json::json!({
+            "name": format!("cb {:02x}", op),
+            "initial": {"pc": 0x100, "sp": 0xFFFE, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0xC0, "l": 0,
+                "ime": 0, "ie": 0, "ram": [[0x100, 0xCB], [0x101, op], [0xC000, 0x80]]},
+            "final": {"pc": 0x102, "sp": 0xFFFE, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": f, "h": 0xC0, "l": 0,
+                "ime": 0, "ram": [[0x100, 0xCB], [0x101, op], [0xC000, res]]},
+            "cycles": cycles,
+        });
+    }
+
+    #[test]
+    fn passes() {
+        assert_eq!(run_test(&cb_hl(0x16, 0x00, 0x90, 3)), Ok(vec![])); // RL [HL]
+        assert_eq!(run_test(&cb_hl(0x26, 0x00, 0x90, 3)), Ok(vec![])); // SLA [HL]
+        assert_eq!(run_test(&cb_hl(0x06, 0x01, 0x10, 3)), Ok(vec![])); // RLC [HL]
+    }
+
+    #[test]
+    fn wrong_cycle() {
+        // The write a cycle early, where the vector has the read
+        let errs = run_test(&cb_hl(0x16, 0x00, 0x90, 2)).unwrap();
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert!(errs[0].starts_with("bus: got"), "{}", errs[0]);
+        // A wrong result is caught as well as the bus write
+        let errs = run_test(&cb_hl(0x16, 0x01, 0x10, 3)).unwrap();
+        assert_eq!(errs.len(), 3, "{:?}", errs);
+    }
+}
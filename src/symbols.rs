@@ -0,0 +1,133 @@
+// RGBDS/BGB .sym files, one "bank:addr label" per line, ; starts a comment
+use crate::mem::Mem;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<(u16, u16), String>,
+    by_name: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+        let dat = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Symbols::parse(&dat, &path.display().to_string());
+    }
+
+    // file is for error messages
+    fn parse(dat: &str, file: &str) -> Result<Symbols, String> {
+        let mut syms = Symbols::default();
+        for (i, line) in dat.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut it = line.split_whitespace();
+            let (loc, name) = match (it.next(), it.next()) {
+                (Some(l), Some(n)) => (l, n),
+                _ => return Err(format!("{}:{}: expected bank:addr label", file, i + 1))
+            };
+            let mut ba = loc.split(':').map(|s| u16::from_str_radix(s, 16));
+            match (ba.next(), ba.next()) {
+                (Some(Ok(bank)), Some(Ok(addr))) => syms.add(bank, addr, name),
+                _ => return Err(format!("{}:{}: bad address {}", file, i + 1, loc))
+            }
+        }
+        return Ok(syms);
+    }
+
+    fn add(&mut self, bank: u16, addr: u16, name: &str) {
+        self.by_name.insert(name.to_string(), (bank, addr));
+        // Prefer global labels over local ones at the same address
+        let cur = self.by_addr.entry((bank, addr)).or_insert(name.to_string());
+        if cur.contains('.') && !name.contains('.') {
+            *cur = name.to_string();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.by_name.len();
+    }
+
+    // bank, addr
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        return self.by_name.get(name).cloned();
+    }
+
+    // bank, addr, name for every address with a label
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16, &str)> {
+        return self.by_addr.iter().map(|((b, a), n)| (*b, *a, n.as_str()));
+    }
+
+    pub fn name(&self, bank: u16, addr: u16) -> Option<&str> {
+        return self.by_addr.get(&(bank, addr)).map(|s| s.as_str());
+    }
+
+    // Label at addr in whatever bank is mapped there now
+    pub fn label(&self, mem: &Mem, addr: u16) -> Option<String> {
+        return self.name(mem.bank_of(addr), addr).map(|s| s.to_string());
+    }
+
+    // Closest label at or before addr in the same bank and memory region, as Label or Label+$n
+    pub fn near(&self, bank: u16, addr: u16) -> Option<String> {
+        let start = match addr {
+            0x0000..=0x3FFF => 0x0000,
+            0x4000..=0x7FFF => 0x4000,
+            0x8000..=0x9FFF => 0x8000,
+            0xA000..=0xBFFF => 0xA000,
+            0xC000..=0xCFFF => 0xC000,
+            0xD000..=0xDFFF => 0xD000,
+            0xE000..=0xFF7F => 0xE000,
+            _ => 0xFF80
+        };
+        let ((_, at), name) = self.by_addr.range((bank, start)..=(bank, addr)).next_back()?;
+        return Some(if *at == addr { name.clone() } else { format!("{}+${:X}", name, addr - at) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bank_and_addr() {
+        let s = Symbols::parse("00:0150 Start\n01:4000 Bank1\n  0a:7ffe   End  \n", "t.sym").unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.lookup("Start"), Some((0, 0x150)));
+        assert_eq!(s.lookup("Bank1"), Some((1, 0x4000)));
+        assert_eq!(s.lookup("End"), Some((0xA, 0x7FFE)));
+        assert_eq!(s.name(1, 0x4000), Some("Bank1"));
+        assert_eq!(s.name(0, 0x4000), None);
+        assert_eq!(s.near(1, 0x4010).as_deref(), Some("Bank1+$10"));
+        assert_eq!(s.near(0, 0x4010), None); // other bank
+        assert_eq!(s.near(0, 0x4000), None); // Start is in another region
+    }
+
+    #[test]
+    fn local_labels() {
+        let s = Symbols::parse("00:0200 Main.loop\n00:0200 Main\n00:0204 Main.done\n", "t.sym").unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.name(0, 0x200), Some("Main")); // global wins, whatever the order
+        assert_eq!(s.lookup("Main.loop"), Some((0, 0x200)));
+        assert_eq!(s.name(0, 0x204), Some("Main.done"));
+        assert_eq!(s.near(0, 0x205).as_deref(), Some("Main.done+$1"));
+    }
+
+    #[test]
+    fn comments() {
+        let s = Symbols::parse("; File created by rgblink\n\n00:0100 Entry ; the header\n   ;\n", "t.sym").unwrap();
+        assert_eq!(s.len(), 1);
+        assert_eq!(s.lookup("Entry"), Some((0, 0x100)));
+    }
+
+    #[test]
+    fn bad_lines() {
+        let err = |dat: &str| Symbols::parse(dat, "t.sym").err().unwrap();
+        assert_eq!(err("00:0100 A\n00:0100\n"), "t.sym:2: expected bank:addr label");
+        assert_eq!(err("0100 A\n"), "t.sym:1: bad address 0100");
+        assert_eq!(err("00:01g0 A\n"), "t.sym:1: bad address 00:01g0");
+        assert_eq!(err("100:10000 A\n"), "t.sym:1: bad address 100:10000");
+    }
+}
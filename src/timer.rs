@@ -2,6 +2,7 @@ use crate::consts::*;
 use crate::mem::Mem;
 
 
+#[derive(Clone)]
 pub struct Timer {
     cnt: u32,
     div: u32,
@@ -19,10 +20,17 @@ impl Default for Timer {
 }
 
 impl Timer {
-    pub fn inc(&mut self, cp_clks: u64, gb_mem: &mut Mem) {
-        let tclk = cp_clks - self.prev;
+    // Counts cpu clocks, twice as many per 4 MHz clock in double speed.
+    // double is the speed the clocks since the last call ran at
+    pub fn inc(&mut self, cp_clks: u64, double: bool, gb_mem: &mut Mem) {
+        let tclk = (cp_clks - self.prev) << double as u32;
         self.prev = cp_clks;
-        self.div += tclk as u32;
+        // DIV was reset during those clocks, count from 0 again
+        if std::mem::take(&mut gb_mem.div_reset) {
+            self.div = 0;
+        } else {
+            self.div += tclk as u32;
+        }
         while self.div >= 256 {
             let div = gb_mem.read(DIVTP).wrapping_add(1);
             gb_mem.io[4] = div;